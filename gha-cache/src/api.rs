@@ -40,8 +40,11 @@ const API_VERSION: &str = "6.0-preview.1";
 /// We want to be polite :)
 const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
-/// The default cache version/namespace.
-const DEFAULT_VERSION: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+/// The seed of the cache version/namespace.
+///
+/// This is deliberately not derived from the crate version, so that
+/// upgrading doesn't change the namespace.
+const VERSION_SEED: &str = "gha-cache/0.1.0";
 
 /// The chunk size in bytes.
 ///
@@ -241,13 +244,13 @@ struct RequestStats {
     patch: AtomicUsize,
 }
 
-trait ResponseExt {
+pub(crate) trait ResponseExt {
     async fn check(self) -> Result<()>;
     async fn check_json<T: DeserializeOwned>(self) -> Result<T>;
 }
 
 impl Error {
-    fn init_error<E>(e: E) -> Self
+    pub(crate) fn init_error<E>(e: E) -> Self
     where
         E: std::error::Error + Send + Sync + 'static,
     {
@@ -268,6 +271,14 @@ impl fmt::Display for ApiErrorInfo {
     }
 }
 
+/// Returns the cache version/namespace that [`Api::mutate_version`]
+/// produces when called once with `data`.
+pub fn mutated_version(data: &[u8]) -> String {
+    let mut version_hasher = Sha256::new_with_prefix(VERSION_SEED.as_bytes());
+    version_hasher.update(data);
+    hex::encode(version_hasher.finalize())
+}
+
 impl Api {
     pub fn new(
         credentials: Credentials,
@@ -293,7 +304,7 @@ impl Api {
             .build()
             .map_err(Error::init_error)?;
 
-        let version_hasher = Sha256::new_with_prefix(DEFAULT_VERSION.as_bytes());
+        let version_hasher = Sha256::new_with_prefix(VERSION_SEED.as_bytes());
         let initial_version = hex::encode(version_hasher.clone().finalize());
 
         // Create HTTP client with authorization header
@@ -332,6 +343,11 @@ impl Api {
         self.circuit_breaker_429_tripped.load(Ordering::Relaxed)
     }
 
+    /// Returns the cache version/namespace.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
     /// Mutates the cache version/namespace.
     pub fn mutate_version(&mut self, data: &[u8]) {
         self.version_hasher.update(data);
@@ -58,4 +58,21 @@ impl Credentials {
             service_v2,
         })
     }
+
+    /// Tries to load credentials for the v1 protocol from the environment.
+    ///
+    /// Forgejo and Gitea runners only provide `ACTIONS_CACHE_URL` and
+    /// `ACTIONS_RUNTIME_TOKEN`, and don't implement the v2 cache service.
+    pub fn load_from_env_v1() -> Option<Self> {
+        let cache_url = env::var("ACTIONS_CACHE_URL").ok()?;
+        let runtime_token = env::var("ACTIONS_RUNTIME_TOKEN").ok()?;
+
+        Some(Self {
+            // Never used in v1 mode, but the Twirp client wants a valid URL.
+            results_url: cache_url.clone(),
+            cache_url,
+            runtime_token,
+            service_v2: String::new(),
+        })
+    }
 }
@@ -16,6 +16,7 @@
 pub mod api;
 pub mod credentials;
 mod github;
+pub mod rest;
 mod util;
 
 pub use api::Api;
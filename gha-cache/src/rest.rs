@@ -0,0 +1,261 @@
+//! GitHub REST API client for cache management.
+//!
+//! Unlike the private API in [`crate::api`], the public REST API allows
+//! listing and deleting cache entries of a repository.
+//!
+//! <https://docs.github.com/en/rest/actions/cache>
+
+use reqwest::{
+    header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION},
+    Client,
+};
+use serde::Deserialize;
+
+use crate::api::{Error, ResponseExt};
+
+/// The User-Agent string for the client.
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// The REST API version we implement.
+const API_VERSION: &str = "2022-11-28";
+
+/// The number of entries to request per page.
+///
+/// This is the maximum allowed by the API.
+const PAGE_SIZE: usize = 100;
+
+type Result<T> = std::result::Result<T, Error>;
+
+pub struct RestApi {
+    /// The HTTP client for authenticated requests.
+    client: Client,
+
+    /// The base URL of the API, e.g. `https://api.github.com`.
+    base_url: String,
+
+    /// The repository, as `owner/name`.
+    repository: String,
+}
+
+/// A cache entry of a repository.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheEntry {
+    /// The ID of the cache.
+    pub id: i64,
+
+    /// The Git ref the cache is scoped to.
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+
+    /// The cache key.
+    pub key: String,
+
+    /// The cache version.
+    pub version: String,
+
+    /// When the cache was last accessed, in RFC 3339 format.
+    pub last_accessed_at: String,
+
+    /// When the cache was created, in RFC 3339 format.
+    pub created_at: String,
+
+    /// The size of the cache, in bytes.
+    pub size_in_bytes: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ListCachesResponse {
+    total_count: usize,
+    actions_caches: Vec<CacheEntry>,
+}
+
+impl RestApi {
+    pub fn new(base_url: &str, repository: &str, token: &str) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        let auth_header = {
+            let mut h =
+                HeaderValue::from_str(&format!("Bearer {token}")).map_err(Error::init_error)?;
+            h.set_sensitive(true);
+            h
+        };
+        headers.insert(AUTHORIZATION, auth_header);
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("application/vnd.github+json"),
+        );
+        headers.insert(
+            "X-GitHub-Api-Version",
+            HeaderValue::from_static(API_VERSION),
+        );
+
+        let client = Client::builder()
+            .user_agent(USER_AGENT)
+            .default_headers(headers)
+            .build()
+            .map_err(Error::init_error)?;
+
+        Ok(Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            repository: repository.to_owned(),
+        })
+    }
+
+    /// Lists all cache entries of the repository.
+    pub async fn list_caches(&self) -> Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+
+        for page in 1.. {
+            let res = self
+                .client
+                .get(self.construct_url("actions/caches"))
+                .query(&[("per_page", PAGE_SIZE), ("page", page)])
+                .send()
+                .await?
+                .check_json::<ListCachesResponse>()
+                .await?;
+
+            let num_entries = res.actions_caches.len();
+            entries.extend(res.actions_caches);
+
+            if num_entries < PAGE_SIZE || entries.len() >= res.total_count {
+                break;
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Deletes a cache entry by ID.
+    pub async fn delete_cache(&self, id: i64) -> Result<()> {
+        self.client
+            .delete(self.construct_url(&format!("actions/caches/{id}")))
+            .send()
+            .await?
+            .check()
+            .await
+    }
+
+    fn construct_url(&self, resource: &str) -> String {
+        format!("{}/repos/{}/{}", self.base_url, self.repository, resource)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Serves canned responses, recording the request lines it received.
+    async fn serve(responses: Vec<(&'static str, String)>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let requests2 = requests.clone();
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+
+                let mut buf = Vec::new();
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let mut chunk = [0; 1024];
+                    let read = socket.read(&mut chunk).await.unwrap();
+                    buf.extend_from_slice(&chunk[..read]);
+                }
+
+                let request = String::from_utf8_lossy(&buf);
+                let request_line = request.lines().next().unwrap().to_owned();
+                requests2.lock().unwrap().push(request_line);
+
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (format!("http://{addr}"), requests)
+    }
+
+    fn entry(id: i64) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "ref": "refs/heads/main",
+            "key": format!("key-{id}"),
+            "version": "abc",
+            "last_accessed_at": "2024-01-01T00:00:00.000Z",
+            "created_at": "2024-01-01T00:00:00.000Z",
+            "size_in_bytes": 1024,
+        })
+    }
+
+    #[tokio::test]
+    async fn list_caches_paginates() {
+        let first_page: Vec<_> = (0..PAGE_SIZE as i64).map(entry).collect();
+        let (base_url, requests) = serve(vec![
+            (
+                "200 OK",
+                serde_json::json!({ "total_count": PAGE_SIZE + 1, "actions_caches": first_page })
+                    .to_string(),
+            ),
+            (
+                "200 OK",
+                serde_json::json!({ "total_count": PAGE_SIZE + 1, "actions_caches": [entry(1000)] })
+                    .to_string(),
+            ),
+        ])
+        .await;
+
+        let api = RestApi::new(&base_url, "owner/repo", "token").unwrap();
+        let entries = api.list_caches().await.unwrap();
+
+        assert_eq!(entries.len(), PAGE_SIZE + 1);
+        assert_eq!(entries.last().unwrap().id, 1000);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                "GET /repos/owner/repo/actions/caches?per_page=100&page=1 HTTP/1.1",
+                "GET /repos/owner/repo/actions/caches?per_page=100&page=2 HTTP/1.1",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_cache() {
+        let (base_url, requests) = serve(vec![("204 No Content", String::new())]).await;
+
+        let api = RestApi::new(&base_url, "owner/repo", "token").unwrap();
+        api.delete_cache(42).await.unwrap();
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["DELETE /repos/owner/repo/actions/caches/42 HTTP/1.1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_cache_error() {
+        let (base_url, _) = serve(vec![(
+            "404 Not Found",
+            r#"{"message": "Not Found"}"#.to_owned(),
+        )])
+        .await;
+
+        let api = RestApi::new(&base_url, "owner/repo", "token").unwrap();
+        let err = api.delete_cache(42).await.unwrap_err();
+
+        assert!(matches!(
+            err,
+            Error::ApiError {
+                status: reqwest::StatusCode::NOT_FOUND,
+                ..
+            }
+        ));
+    }
+}
@@ -2,12 +2,20 @@
 //!
 //! This API is intended to be used by nix-installer-action.
 
+use std::path::PathBuf;
+
 use attic::nix_store::StorePath;
-use axum::{extract::Extension, routing::post, Json, Router};
+use axum::{
+    extract::{Extension, Query},
+    routing::{get, post},
+    Json, Router,
+};
 use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
 
 use super::State;
 use crate::error::{Error, Result};
+use crate::gha::UploadStatus;
 
 #[derive(Debug, Clone, Serialize)]
 struct WorkflowStartResponse {
@@ -19,6 +27,55 @@ struct WorkflowFinishResponse {
     num_original_paths: Option<usize>,
     num_final_paths: Option<usize>,
     num_new_paths: Option<usize>,
+    paths_skipped_over_budget: Vec<PathBuf>,
+    paths_abandoned: Vec<PathBuf>,
+    paths_failed: Vec<FailedUpload>,
+    flakehub_uploads_abandoned: bool,
+    flakehub_paths_pushed: usize,
+    flakehub_paths_failed: Vec<FailedUpload>,
+
+    /// Why FlakeHub Cache push sessions failed as a whole, if any did. The
+    /// results of the other sessions are still reported.
+    flakehub_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FailedUpload {
+    path: PathBuf,
+    error: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct WorkflowFinishParams {
+    /// How long to wait for uploads to finish, e.g. `300s` or `5m`.
+    deadline: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct StatusResponse {
+    github_actions_cache: Option<GhaStatus>,
+    flakehub_cache: Option<FlakeHubStatus>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct GhaStatus {
+    #[serde(flatten)]
+    uploads: UploadStatus,
+    bytes_uploaded: usize,
+    paths_skipped_over_budget: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FlakeHubStatus {
+    /// Whether paths are only substituted from the cache, not pushed.
+    read_only: bool,
+
+    /// Paths handed to the push sessions.
+    ///
+    /// This is the only progress that's available: the push sessions don't
+    /// report which paths they have pushed (or how many closure members they
+    /// added) until `/api/workflow-finish` waits for them.
+    paths_queued: usize,
 }
 
 pub fn get_router() -> Router {
@@ -26,6 +83,7 @@ pub fn get_router() -> Router {
         .route("/api/workflow-start", post(workflow_start))
         .route("/api/workflow-finish", post(workflow_finish))
         .route("/api/enqueue-paths", post(post_enqueue_paths))
+        .route("/api/status", get(status))
 }
 
 /// Record existing paths.
@@ -51,13 +109,47 @@ async fn workflow_start(Extension(state): Extension<State>) -> Result<Json<Workf
     Ok(Json(reply))
 }
 
+/// Report the progress of the uploads.
+async fn status(Extension(state): Extension<State>) -> Json<StatusResponse> {
+    let github_actions_cache = state.gha_cache.as_ref().map(|gha_cache| GhaStatus {
+        uploads: gha_cache.status(),
+        bytes_uploaded: state.metrics.bytes_uploaded.get(),
+        paths_skipped_over_budget: state.metrics.paths_skipped_over_budget.get(),
+    });
+
+    let flakehub_cache = state
+        .flakehub_state
+        .read()
+        .await
+        .as_ref()
+        .map(|flakehub_state| FlakeHubStatus {
+            read_only: flakehub_state.is_read_only(),
+            paths_queued: flakehub_state.paths_queued(),
+        });
+
+    Json(StatusResponse {
+        github_actions_cache,
+        flakehub_cache,
+    })
+}
+
 /// Push new paths and shut down.
 async fn workflow_finish(
     Extension(state): Extension<State>,
+    Query(params): Query<WorkflowFinishParams>,
 ) -> Result<Json<WorkflowFinishResponse>> {
     tracing::info!("Workflow finished");
 
-    let response = if let Some(original_paths) = &state.original_paths {
+    let deadline = params
+        .deadline
+        .map(|deadline| {
+            humantime::parse_duration(&deadline)
+                .map(|duration| Instant::now() + duration)
+                .map_err(|_| Error::BadRequest)
+        })
+        .transpose()?;
+
+    let mut response = if let Some(original_paths) = &state.original_paths {
         let original_paths = original_paths.lock().await;
         let final_paths = crate::util::get_store_paths(&state.store).await?;
         let new_paths = final_paths
@@ -74,6 +166,13 @@ async fn workflow_finish(
             num_original_paths: Some(num_original_paths),
             num_final_paths: Some(num_final_paths),
             num_new_paths: Some(num_new_paths),
+            paths_skipped_over_budget: Vec::new(),
+            paths_abandoned: Vec::new(),
+            paths_failed: Vec::new(),
+            flakehub_uploads_abandoned: false,
+            flakehub_paths_pushed: 0,
+            flakehub_paths_failed: Vec::new(),
+            flakehub_error: None,
         };
 
         state.metrics.num_original_paths.set(num_original_paths);
@@ -83,7 +182,7 @@ async fn workflow_finish(
         // NOTE(cole-h): If we're substituting from an upstream cache, those paths won't have the
         // post-build-hook run on it, so we diff the store to ensure we cache everything we can.
         tracing::info!("Diffing the store and uploading any new paths before we shut down");
-        enqueue_paths(&state, new_paths).await?;
+        enqueue_paths(&state, new_paths, None).await?;
 
         reply
     } else {
@@ -91,21 +190,124 @@ async fn workflow_finish(
             num_original_paths: None,
             num_final_paths: None,
             num_new_paths: None,
+            paths_skipped_over_budget: Vec::new(),
+            paths_abandoned: Vec::new(),
+            paths_failed: Vec::new(),
+            flakehub_uploads_abandoned: false,
+            flakehub_paths_pushed: 0,
+            flakehub_paths_failed: Vec::new(),
+            flakehub_error: None,
         }
     };
 
     if let Some(gha_cache) = &state.gha_cache {
         tracing::info!("Waiting for GitHub action cache uploads to finish");
-        gha_cache.shutdown().await?;
+        let report = gha_cache.shutdown(deadline).await?;
+
+        response.paths_skipped_over_budget = report
+            .skipped_over_budget
+            .iter()
+            .map(|path| state.store.get_full_path(path))
+            .collect();
+
+        if !response.paths_skipped_over_budget.is_empty() {
+            tracing::warn!(
+                paths = ?response.paths_skipped_over_budget,
+                "Skipped {} paths because the upload budget was exhausted",
+                response.paths_skipped_over_budget.len()
+            );
+        }
+
+        response.paths_abandoned = report
+            .abandoned
+            .iter()
+            .map(|path| state.store.get_full_path(path))
+            .collect();
+
+        if !response.paths_abandoned.is_empty() {
+            tracing::warn!(
+                paths = ?response.paths_abandoned,
+                "Abandoned {} paths because the deadline was reached",
+                response.paths_abandoned.len()
+            );
+        }
+
+        response.paths_failed = report
+            .failed
+            .into_iter()
+            .map(|(path, error)| FailedUpload {
+                path: state.store.get_full_path(&path),
+                error,
+            })
+            .collect();
+
+        if !response.paths_failed.is_empty() {
+            tracing::warn!(
+                paths = ?response.paths_failed,
+                "Failed to upload {} paths",
+                response.paths_failed.len()
+            );
+        }
     }
 
     if let Some(attic_state) = state.flakehub_state.write().await.take() {
         tracing::info!("Waiting for FlakeHub cache uploads to finish");
-        let paths = attic_state.push_session.wait().await?;
+        let wait = crate::flakehub::wait(attic_state);
 
-        let paths = paths.keys().map(|s| s.name()).collect::<Vec<_>>();
+        let report = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, wait).await.ok(),
+            None => Some(wait.await),
+        };
 
-        tracing::info!(?paths, "FlakeHub Cache uploads completed");
+        // Failures are reported rather than returned, so that the rest of
+        // the shutdown still happens.
+        match report {
+            Some(Ok(report)) => {
+                let mut pushed = Vec::new();
+                for (path, res) in report.paths {
+                    match res {
+                        Ok(()) => pushed.push(path.name()),
+                        Err(err) => response.flakehub_paths_failed.push(FailedUpload {
+                            path: state.store.get_full_path(&path),
+                            error: format!("{err:#}"),
+                        }),
+                    }
+                }
+
+                response.flakehub_paths_pushed = pushed.len();
+                state.metrics.flakehub_paths_pushed.set(pushed.len());
+                state
+                    .metrics
+                    .flakehub_paths_failed
+                    .set(response.flakehub_paths_failed.len());
+
+                tracing::info!(paths = ?pushed, "FlakeHub Cache uploads completed");
+
+                if !response.flakehub_paths_failed.is_empty() {
+                    tracing::warn!(
+                        paths = ?response.flakehub_paths_failed,
+                        "Failed to push {} paths to the FlakeHub Cache",
+                        response.flakehub_paths_failed.len()
+                    );
+                }
+
+                if !report.session_errors.is_empty() {
+                    let err = report.session_errors.join("; ");
+                    tracing::error!("FlakeHub Cache uploads failed: {}", err);
+                    response.flakehub_error = Some(err);
+                }
+            }
+            Some(Err(err)) => {
+                tracing::error!("FlakeHub Cache uploads failed: {}", err);
+                response.flakehub_error = Some(err.to_string());
+            }
+            None => {
+                tracing::warn!(
+                    "Abandoned the FlakeHub Cache uploads because the deadline was reached"
+                );
+                response.flakehub_uploads_abandoned = true;
+            }
+        }
     } else {
         tracing::info!("FlakeHub cache is not enabled, not uploading anything to it");
     }
@@ -126,6 +328,10 @@ async fn workflow_finish(
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EnqueuePathsRequest {
     pub store_paths: Vec<String>,
+
+    /// The derivation that produced the paths, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deriver: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -145,20 +351,31 @@ async fn post_enqueue_paths(
         .map(|path| state.store.follow_store_path(path).map_err(Error::Attic))
         .collect::<Result<Vec<_>>>()?;
 
-    enqueue_paths(&state, store_paths).await?;
+    enqueue_paths(&state, store_paths, req.deriver).await?;
 
     Ok(Json(EnqueuePathsResponse {}))
 }
 
-pub async fn enqueue_paths(state: &State, store_paths: Vec<StorePath>) -> Result<()> {
+/// Schedules paths for uploading.
+///
+/// `deriver` is the derivation that produced the paths, if known.
+pub async fn enqueue_paths(
+    state: &State,
+    store_paths: Vec<StorePath>,
+    deriver: Option<PathBuf>,
+) -> Result<()> {
+    // The deriver is read later on, so make sure it's actually a derivation.
+    let deriver = deriver.filter(|deriver| {
+        deriver.extension().is_some_and(|ext| ext == "drv")
+            && state.store.parse_store_path(deriver).is_ok()
+    });
+
     if let Some(gha_cache) = &state.gha_cache {
-        gha_cache
-            .enqueue_paths(state.store.clone(), store_paths.clone())
-            .await?;
+        gha_cache.enqueue_paths(store_paths.clone(), deriver)?;
     }
 
     if let Some(flakehub_state) = &*state.flakehub_state.read().await {
-        crate::flakehub::enqueue_paths(flakehub_state, store_paths).await?;
+        crate::flakehub::enqueue_paths(flakehub_state, store_paths)?;
     }
 
     Ok(())
@@ -0,0 +1,190 @@
+//! NAR compression.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use async_compression::tokio::bufread::{BrotliEncoder, XzEncoder, ZstdEncoder};
+use async_compression::zstd::CParameter;
+use async_compression::Level;
+use attic::nix_store::StorePath;
+use tokio::io::{AsyncBufRead, AsyncRead};
+
+use crate::narinfo::Compression;
+
+/// File name extensions of store paths whose contents are already
+/// compressed, and don't benefit from compressing them again.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    ".7z", ".br", ".bz2", ".gz", ".jar", ".jpeg", ".jpg", ".lz", ".lzma", ".mp3", ".mp4", ".png",
+    ".tbz", ".tgz", ".txz", ".webm", ".webp", ".whl", ".xz", ".zip", ".zst", ".zstd",
+];
+
+/// The compression to apply to uploaded NARs.
+///
+/// This is parsed from `none`, `xz`, `zstd`, `zstd:<level>`, `br` or
+/// `auto`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NarCompression {
+    /// No compression.
+    None,
+
+    /// xz compression.
+    Xz,
+
+    /// Multi-threaded zstd compression, at the given level or zstd's default.
+    Zstd(Option<i32>),
+
+    /// Brotli compression.
+    Brotli,
+
+    /// zstd, unless the path looks like it's already compressed.
+    Auto,
+}
+
+impl NarCompression {
+    /// Picks the compression for a specific store path.
+    ///
+    /// Never returns [`NarCompression::Auto`].
+    pub fn for_path(self, path: &StorePath) -> Self {
+        match self {
+            Self::Auto => {
+                let name = path.name();
+
+                if COMPRESSED_EXTENSIONS.iter().any(|ext| name.ends_with(ext)) {
+                    Self::None
+                } else {
+                    Self::Zstd(None)
+                }
+            }
+            c => c,
+        }
+    }
+
+    /// Returns the file name extension of NARs with this compression.
+    ///
+    /// Cache keys are looked up by prefix, so no extension may be a prefix
+    /// of another; otherwise a lookup of an uncompressed NAR would find a
+    /// compressed one, for instance.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::None => "nar.none",
+            Self::Xz => "nar.xz",
+            Self::Zstd(_) | Self::Auto => "nar.zstd",
+            Self::Brotli => "nar.br",
+        }
+    }
+
+    /// Returns the compression to record in the narinfo.
+    pub fn narinfo_compression(self) -> Compression {
+        match self {
+            Self::None => Compression::None,
+            Self::Xz => Compression::Xz,
+            Self::Zstd(_) | Self::Auto => Compression::Zstd,
+            Self::Brotli => Compression::Brotli,
+        }
+    }
+
+    /// Returns the parameters for the binary cache store in `nix.conf`,
+    /// which Nix uses when it uploads paths itself.
+    pub fn nix_store_params(self) -> String {
+        match self {
+            Self::None => "compression=none".to_owned(),
+            Self::Xz => "compression=xz&parallel-compression=true".to_owned(),
+            Self::Zstd(None) | Self::Auto => {
+                "compression=zstd&parallel-compression=true".to_owned()
+            }
+            Self::Zstd(Some(level)) => {
+                format!("compression=zstd&parallel-compression=true&compression-level={level}")
+            }
+            Self::Brotli => "compression=br".to_owned(),
+        }
+    }
+
+    /// Wraps a reader in the encoder for this compression.
+    pub fn encode<R>(self, reader: R) -> Box<dyn AsyncRead + Send + Unpin>
+    where
+        R: AsyncBufRead + Send + Unpin + 'static,
+    {
+        match self {
+            Self::None => Box::new(reader),
+            Self::Xz => Box::new(XzEncoder::new(reader)),
+            Self::Zstd(level) => Box::new(ZstdEncoder::with_quality_and_params(
+                reader,
+                level.map_or(Level::Default, Level::Precise),
+                &[CParameter::nb_workers(zstd_workers())],
+            )),
+            Self::Auto => Self::Zstd(None).encode(reader),
+            Self::Brotli => Box::new(BrotliEncoder::new(reader)),
+        }
+    }
+}
+
+impl FromStr for NarCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "xz" => Ok(Self::Xz),
+            "zstd" => Ok(Self::Zstd(None)),
+            "br" => Ok(Self::Brotli),
+            "auto" => Ok(Self::Auto),
+            s => match s.strip_prefix("zstd:") {
+                Some(level) => level
+                    .parse()
+                    .map(|level| Self::Zstd(Some(level)))
+                    .map_err(|_| format!("invalid zstd level \"{level}\"")),
+                None => Err(format!(
+                    "unknown compression \"{s}\" (expected none, xz, zstd, zstd:<level>, br or auto)"
+                )),
+            },
+        }
+    }
+}
+
+impl Display for NarCompression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Xz => write!(f, "xz"),
+            Self::Zstd(None) => write!(f, "zstd"),
+            Self::Zstd(Some(level)) => write!(f, "zstd:{level}"),
+            Self::Brotli => write!(f, "br"),
+            Self::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Returns the number of zstd worker threads to use.
+fn zstd_workers() -> u32 {
+    std::thread::available_parallelism()
+        .map(|n| n.get() as u32)
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: &[NarCompression] = &[
+        NarCompression::None,
+        NarCompression::Xz,
+        NarCompression::Zstd(None),
+        NarCompression::Zstd(Some(19)),
+        NarCompression::Brotli,
+        NarCompression::Auto,
+    ];
+
+    #[test]
+    fn extensions_are_not_prefixes_of_each_other() {
+        for a in ALL {
+            for b in ALL {
+                if a.extension() != b.extension() {
+                    assert!(
+                        !b.extension().starts_with(a.extension()),
+                        "the extension of {a} is a prefix of the extension of {b}"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Minimal parsing of `.drv` files.
+//!
+//! We only need the `system` of a derivation, so rather than parsing the
+//! whole ATerm, we skip over the fields that precede it:
+//!
+//! ```text
+//! Derive([outputs],[inputDrvs],[inputSrcs],"x86_64-linux","/bin/sh",[args],[env])
+//! ```
+
+use std::path::Path;
+
+/// Returns the `system` of the derivation at `drv_path`.
+pub async fn read_system(drv_path: &Path) -> Option<String> {
+    let contents = tokio::fs::read_to_string(drv_path)
+        .await
+        .map_err(|e| {
+            tracing::debug!("Failed to read {}: {}", drv_path.display(), e);
+        })
+        .ok()?;
+
+    parse_system(&contents)
+}
+
+fn parse_system(drv: &str) -> Option<String> {
+    let mut rest = if let Some(rest) = drv.strip_prefix("DrvWithVersion(") {
+        // `DrvWithVersion("<version>",[outputs],...)`
+        let (_, rest) = parse_string(rest)?;
+        rest.strip_prefix(',')?
+    } else {
+        drv.strip_prefix("Derive(")?
+    };
+
+    // Skip the outputs, input derivations and input sources.
+    for _ in 0..3 {
+        rest = skip_list(rest)?.strip_prefix(',')?;
+    }
+
+    let (system, _) = parse_string(rest)?;
+    Some(system)
+}
+
+/// Skips a bracketed list, returning the remainder.
+fn skip_list(s: &str) -> Option<&str> {
+    let mut depth = 0;
+    let mut rest = s;
+
+    loop {
+        let c = rest.chars().next()?;
+
+        if c == '"' {
+            rest = parse_string(rest)?.1;
+            continue;
+        }
+
+        rest = &rest[c.len_utf8()..];
+
+        match c {
+            '[' | '(' => depth += 1,
+            ']' | ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(rest);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Parses a string literal, returning it and the remainder.
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut value = String::new();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_derive() {
+        let drv = r#"Derive([("out","/nix/store/aaa-hello","","")],[("/nix/store/bbb-bash.drv",["out"])],["/nix/store/ccc-builder.sh"],"x86_64-linux","/bin/sh",["-e"],[("name","hello")])"#;
+        assert_eq!(parse_system(drv).as_deref(), Some("x86_64-linux"));
+    }
+
+    #[test]
+    fn parses_drv_with_version() {
+        let drv = r#"DrvWithVersion("xp-dyn-drv",[("out","","r:sha256","")],[],[],"aarch64-darwin","/bin/sh",[],[])"#;
+        assert_eq!(parse_system(drv).as_deref(), Some("aarch64-darwin"));
+    }
+
+    #[test]
+    fn skips_escapes_and_brackets_in_strings() {
+        let drv = r#"Derive([("out","/nix/store/aaa-a]b)c\"d","","")],[],["/nix/store/ccc-[x\\"],"i686-linux","/bin/sh",[],[])"#;
+        assert_eq!(parse_system(drv).as_deref(), Some("i686-linux"));
+    }
+
+    #[test]
+    fn unescapes_the_system() {
+        let drv = r#"Derive([],[],[],"x86_64-\"linux\"\n","/bin/sh",[],[])"#;
+        assert_eq!(parse_system(drv).as_deref(), Some("x86_64-\"linux\"\n"));
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let drv = r#"Derive([("out","/nix/store/aaa-hello","","")],[],[],"x86_64-linux","/bin/sh",[],[])"#;
+
+        for len in 0..drv.find(",\"/bin/sh\"").unwrap() {
+            assert_eq!(parse_system(&drv[..len]), None, "{:?}", &drv[..len]);
+        }
+        assert_eq!(parse_system("Something([],[],[],\"x86_64-linux\")"), None);
+    }
+}
@@ -4,11 +4,19 @@ use std::fmt::{self, Display};
 pub enum Environment {
     GitHubActions,
     GitLabCI,
+    Forgejo,
+    Buildkite,
     Other,
 }
 
 impl Environment {
     pub fn determine() -> Self {
+        // act_runner also sets `GITHUB_ACTIONS` for compatibility, so this
+        // needs to be checked first.
+        if env_var_is_true("FORGEJO_ACTIONS") || env_var_is_true("GITEA_ACTIONS") {
+            return Environment::Forgejo;
+        }
+
         if env_var_is_true("GITHUB_ACTIONS") {
             return Environment::GitHubActions;
         }
@@ -17,6 +25,10 @@ impl Environment {
             return Environment::GitLabCI;
         }
 
+        if env_var_is_true("BUILDKITE") {
+            return Environment::Buildkite;
+        }
+
         Environment::Other
     }
 
@@ -27,6 +39,10 @@ impl Environment {
     pub fn is_gitlab_ci(&self) -> bool {
         matches!(self, Self::GitLabCI)
     }
+
+    pub fn is_forgejo(&self) -> bool {
+        matches!(self, Self::Forgejo)
+    }
 }
 
 impl Display for Environment {
@@ -39,6 +55,8 @@ impl Display for Environment {
             match self {
                 GitHubActions => "GitHub Actions",
                 GitLabCI => "GitLab CI",
+                Forgejo => "Forgejo Actions",
+                Buildkite => "Buildkite",
                 Other => "an unspecified environment",
             }
         )
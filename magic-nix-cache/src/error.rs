@@ -34,9 +34,6 @@ pub enum Error {
     #[error("Got HTTP response {0} getting the cache name from FlakeHub: {1}")]
     GetCacheName(reqwest::StatusCode, String),
 
-    #[error("netrc parse error: {0}")]
-    Netrc(netrc_rs::Error),
-
     #[error("Cannot find netrc credentials for {0}")]
     MissingCreds(String),
 
@@ -1,88 +1,269 @@
-use crate::env::Environment;
 use crate::error::{Error, Result};
+use crate::policy::{Pattern, Policy, SkippedPaths};
+use crate::token::TokenProvider;
+use crate::util;
 use crate::DETERMINATE_NETRC_PATH;
 use anyhow::Context;
 use attic::cache::CacheName;
 use attic::nix_store::{NixStore, StorePath};
 use attic_client::push::{PushSession, PushSessionConfig};
 use attic_client::{
-    api::ApiClient,
+    api::{ApiClient, ApiError},
     config::ServerConfig,
     push::{PushConfig, Pusher},
 };
 
-use reqwest::header::HeaderValue;
-use reqwest::Url;
+use base64::Engine;
+use reqwest::{StatusCode, Url};
 use serde::Deserialize;
-use std::os::unix::fs::MetadataExt;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::task::JoinSet;
 use uuid::Uuid;
 
-const USER_AGENT: &str = "magic-nix-cache";
+pub const USER_AGENT: &str = "magic-nix-cache";
+
+/// How long to wait before refreshing a JWT whose lifetime we can't tell.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+/// The least amount of time to wait between JWT refreshes.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The most amount of time to wait between JWT refreshes.
+const MAX_REFRESH_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// How often to check whether FlakeHub still accepts the token between
+/// refreshes.
+const TOKEN_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often to check the netrc file for changes if it can't be watched.
+const NETRC_POLL_INTERVAL: Duration = Duration::from_secs(3);
 
 pub struct State {
     #[allow(dead_code)]
     pub substituter: Url,
 
-    pub push_session: PushSession,
+    /// The push sessions, which are shared with the tasks that filter
+    /// enqueued paths.
+    pushers: Arc<Pushers>,
+
+    /// Applies the upload policy to the closures of enqueued paths, if
+    /// there is a policy.
+    closure_filter: Option<Arc<ClosureFilter>>,
+
+    /// Enqueued paths that are being filtered.
+    filtering: std::sync::Mutex<JoinSet<()>>,
+}
+
+impl State {
+    /// Returns whether nothing is pushed to any of the caches.
+    pub fn is_read_only(&self) -> bool {
+        self.pushers
+            .caches
+            .iter()
+            .all(|cache| cache.push_session.is_none())
+    }
+
+    /// Returns the number of paths handed to the push sessions.
+    pub fn paths_queued(&self) -> usize {
+        self.pushers.paths_queued.load(Ordering::Relaxed)
+    }
+}
+
+struct Pushers {
+    /// The caches to push to.
+    caches: Vec<Cache>,
+
+    /// Rules that send paths to only some of the caches.
+    routes: Vec<Route>,
+
+    /// The number of paths handed to the push sessions.
+    paths_queued: AtomicUsize,
+}
+
+/// Works out which members of the closures of enqueued paths may be
+/// pushed.
+///
+/// The push sessions would push entire closures, including the paths that
+/// the upload policy skips, so with a policy they only get the paths
+/// themselves, and we work out the closure instead.
+struct ClosureFilter {
+    store: Arc<NixStore>,
+    policy: Arc<Policy>,
+
+    /// Whether to push the closures of the paths, rather than just the
+    /// paths.
+    closure: bool,
+
+    /// Counts the paths that `policy` skips.
+    skipped_paths: Arc<SkippedPaths>,
+}
+
+impl ClosureFilter {
+    async fn filter(&self, store_paths: Vec<StorePath>) -> Result<Vec<StorePath>> {
+        let closure = if self.closure {
+            self.store
+                .compute_fs_closure_multi(store_paths, false, false, false)
+                .await?
+        } else {
+            store_paths
+        };
+
+        let path_infos = util::query_path_infos(&self.store, closure).await?;
+
+        let allowed = self
+            .policy
+            .filter_closure(&self.store, path_infos, &self.skipped_paths)?;
+
+        Ok(allowed.into_iter().map(|(path, _)| path).collect())
+    }
+}
+
+struct Cache {
+    /// The flake whose cache this is, or `None` for the project that the
+    /// token belongs to.
+    flake_name: Option<String>,
+
+    /// The push session, unless the cache is read-only.
+    push_session: Option<PushSession>,
 }
 
+impl Cache {
+    fn describe(&self) -> &str {
+        describe_flake(self.flake_name.as_deref())
+    }
+}
+
+fn describe_flake(flake_name: Option<&str>) -> &str {
+    flake_name.unwrap_or("the default project")
+}
+
+/// Sends the paths whose names match a pattern only to the cache of one
+/// flake.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pattern: Pattern,
+    flake_name: String,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (pattern, flake_name) = s
+            .rsplit_once('=')
+            .ok_or_else(|| "expected PATTERN=FLAKE".to_owned())?;
+
+        Ok(Self {
+            pattern: pattern.parse().map_err(|e| format!("{e}"))?,
+            flake_name: flake_name.to_owned(),
+        })
+    }
+}
+
+/// Whether to push to the FlakeHub Cache.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum FlakeHubMode {
+    /// Only substitute from the cache, e.g. for untrusted pull requests.
+    ReadOnly,
+
+    /// Substitute from the cache and push to it, if the token allows it.
+    ReadWrite,
+}
+
+/// Command-line options for pushing to the FlakeHub Cache.
+#[derive(clap::Args, Debug, Clone)]
+pub struct PushOptions {
+    /// Whether to push to the FlakeHub Cache.
+    ///
+    /// In `read-write` mode, the cache falls back to `read-only` if the
+    /// token isn't allowed to push.
+    #[arg(long = "flakehub-mode", value_enum, default_value_t = FlakeHubMode::ReadWrite)]
+    pub mode: FlakeHubMode,
+
+    /// The number of store paths to push to the FlakeHub Cache at the same time.
+    ///
+    /// Defaults to the number of available CPUs, but at least 5.
+    #[arg(long = "flakehub-push-workers", default_value_t = default_push_workers())]
+    pub workers: usize,
+
+    /// Always send the metadata of pushed paths in the request body
+    /// rather than in a header.
+    ///
+    /// Needed for paths with so many references that their metadata
+    /// exceeds the header size limits of proxies along the way.
+    #[arg(long = "flakehub-force-preamble")]
+    pub force_preamble: bool,
+
+    /// Only push the paths themselves to the FlakeHub Cache, not their
+    /// closures.
+    ///
+    /// Implied by `--upload-scope outputs`.
+    #[arg(long = "flakehub-no-closure")]
+    pub no_closure: bool,
+
+    /// Push paths even if the upstream caches of the FlakeHub Cache (such
+    /// as cache.nixos.org) already have them.
+    #[arg(long = "flakehub-ignore-upstream-cache-filter")]
+    pub ignore_upstream_cache_filter: bool,
+
+    /// Only push the paths whose name matches PATTERN to the cache of
+    /// FLAKE, which must be one of the `--flakehub-flake-name` values.
+    ///
+    /// Patterns are the same as for `--include`. Paths that match no
+    /// route are pushed to every cache.
+    #[arg(long = "flakehub-route", value_name = "PATTERN=FLAKE")]
+    pub routes: Vec<Route>,
+}
+
+fn default_push_workers() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .max(5)
+}
+
+/// Sets up pushing to the FlakeHub Cache.
+///
+/// Paths that `policy` skips are counted in `skipped_paths`.
+#[allow(clippy::too_many_arguments)]
 pub async fn init_cache(
-    environment: Environment,
+    token_provider: Option<Box<dyn TokenProvider>>,
     flakehub_api_server: &Url,
     flakehub_cache_server: &Url,
-    flakehub_flake_name: &Option<String>,
+    flakehub_flake_names: &[String],
+    mut push_options: PushOptions,
     store: Arc<NixStore>,
+    policy: Arc<Policy>,
+    skipped_paths: Arc<SkippedPaths>,
     auth_method: &super::FlakeHubAuthSource,
 ) -> Result<State> {
     // Parse netrc to get the credentials for api.flakehub.com.
     let netrc_path = auth_method.as_path_buf();
     let NetrcInfo {
-        netrc,
         flakehub_cache_server_hostname,
         flakehub_login,
         flakehub_password,
     } = extract_info_from_netrc(&netrc_path, flakehub_api_server, flakehub_cache_server).await?;
 
     if let super::FlakeHubAuthSource::Netrc(netrc_path) = auth_method {
-        // Append an entry for the FlakeHub cache server to netrc.
-        if !netrc
-            .machines
-            .iter()
-            .any(|machine| machine.name.as_ref() == Some(&flakehub_cache_server_hostname))
-        {
-            let mut netrc_file = tokio::fs::OpenOptions::new()
-                .create(false)
-                .append(true)
-                .open(netrc_path)
-                .await
-                .map_err(|e| {
-                    Error::Internal(format!(
-                        "Failed to open {} for appending: {}",
-                        netrc_path.display(),
-                        e
-                    ))
-                })?;
-
-            netrc_file
-                .write_all(
-                    format!(
-                        "\nmachine {flakehub_cache_server_hostname} login {flakehub_login} password {flakehub_password}\n\n",
-                    )
-                    .as_bytes(),
-                )
-                .await
-                .map_err(|e| {
-                    Error::Internal(format!(
-                        "Failed to write credentials to {}: {}",
-                        netrc_path.display(),
-                        e
-                    ))
-                })?;
-        }
+        // Add an entry for the FlakeHub cache server to netrc.
+        let hostname = flakehub_cache_server_hostname.clone();
+        let login = flakehub_login.clone();
+        let password = flakehub_password.clone();
+
+        crate::netrc::edit(netrc_path, move |netrc| {
+            if !netrc.has_machine(&hostname) {
+                netrc.add_machine(&hostname, &login, &password);
+            }
+        })
+        .await?;
     }
 
     let server_config = ServerConfig {
@@ -93,133 +274,237 @@ pub async fn init_cache(
     };
     let api = ApiClient::from_server_config(server_config)?;
 
-    // Periodically refresh JWT when not in GitLab CI (which doesn't have a way for us to request a
-    // new token). If we're running in GitHub or Buildkite, and the auth source is determinate-nixd,
-    // we'll refresh the auth info whenever the netrc file is rewritten.
-    if !environment.is_gitlab_ci() {
-        match auth_method {
-            super::FlakeHubAuthSource::Netrc(path) => {
-                if environment.is_github_actions() {
-                    let netrc_path_clone = path.to_path_buf();
-                    let initial_github_jwt_clone = flakehub_password.clone();
-                    let api_clone = api.clone();
-
-                    tokio::task::spawn(refresh_github_actions_jwt_worker(
-                        netrc_path_clone,
-                        initial_github_jwt_clone,
-                        api_clone,
-                    ));
-                } else {
-                    tracing::warn!(
-                        "not running in GitHub Actions -- netrc auth source will not be refreshed!"
-                    );
-                }
+    // Without a flake name, FlakeHub picks the project from the token.
+    let flake_names: Vec<Option<&str>> = if flakehub_flake_names.is_empty() {
+        vec![None]
+    } else {
+        flakehub_flake_names
+            .iter()
+            .map(|name| Some(name.as_str()))
+            .collect()
+    };
+
+    for route in &push_options.routes {
+        if !flakehub_flake_names.contains(&route.flake_name) {
+            return Err(Error::Config(format!(
+                "--flakehub-route refers to '{}', which is not one of the --flakehub-flake-name values",
+                route.flake_name
+            )));
+        }
+    }
+
+    let closure_filter = (!policy.is_empty()).then(|| {
+        let closure = !push_options.no_closure;
+        push_options.no_closure = true;
+
+        Arc::new(ClosureFilter {
+            store: store.clone(),
+            policy,
+            closure,
+            skipped_paths,
+        })
+    });
+
+    let mut caches = Vec::with_capacity(flake_names.len());
+    // A cache that we push to, to check whether the token is still
+    // accepted.
+    let mut probe_cache = None;
+    for flake_name in flake_names {
+        // Get the cache UUID for this project.
+        let cache_name = get_cache_name(
+            flakehub_api_server,
+            flake_name,
+            &flakehub_login,
+            &flakehub_password,
+        )
+        .await?;
+
+        tracing::info!("Using cache {:?}", cache_name);
+
+        let cache = unsafe { CacheName::new_unchecked(cache_name) };
+
+        let description = describe_flake(flake_name);
+
+        let push_session = match push_options.mode {
+            FlakeHubMode::ReadOnly => {
+                tracing::info!(
+                    "FlakeHub cache of {description} is read-only, not pushing anything to it"
+                );
+                None
             }
-            crate::FlakeHubAuthSource::DeterminateNixd => {
-                let api_clone = api.clone();
-                let netrc_file = PathBuf::from(DETERMINATE_NETRC_PATH);
-                let flakehub_api_server_clone = flakehub_api_server.clone();
-                let flakehub_cache_server_clone = flakehub_cache_server.clone();
-
-                let initial_meta = tokio::fs::metadata(&netrc_file).await.map_err(|e| {
-                    Error::Io(e, format!("getting metadata of {}", netrc_file.display()))
-                })?;
-                let initial_inode = initial_meta.ino();
-
-                tokio::task::spawn(refresh_determinate_token_worker(
-                    netrc_file,
-                    initial_inode,
-                    flakehub_api_server_clone,
-                    flakehub_cache_server_clone,
-                    api_clone,
-                ));
+            FlakeHubMode::ReadWrite if token_rejected(&api, &cache).await => {
+                tracing::warn!(
+                    "The FlakeHub token isn't allowed to push to the cache of {description}, continuing in read-only mode"
+                );
+                None
             }
-        }
-    } else {
-        tracing::warn!(
-            "running in GitLab CI -- auth cannot be refreshed, so don't take too long..."
-        );
+            FlakeHubMode::ReadWrite => {
+                probe_cache.get_or_insert_with(|| cache.clone());
+                Some(
+                    start_push_session(store.clone(), api.clone(), cache, push_options.clone())
+                        .await?,
+                )
+            }
+        };
+
+        caches.push(Cache {
+            flake_name: flake_name.map(str::to_owned),
+            push_session,
+        });
     }
 
-    // Get the cache UUID for this project.
-    let cache_name = {
-        let mut url = flakehub_api_server
-            .join("project")
-            .map_err(|_| Error::Config(format!("bad URL '{flakehub_api_server}'")))?;
-
-        if let Some(flakehub_flake_name) = flakehub_flake_name {
-            if !flakehub_flake_name.is_empty() {
-                url = flakehub_api_server
-                    .join(&format!("project/{flakehub_flake_name}"))
-                    .map_err(|_| Error::Config(format!("bad URL '{flakehub_api_server}'")))?;
+    // Periodically refresh the JWT if there's a way to get a new one. If the auth source is
+    // determinate-nixd, it refreshes the token itself, and we pick up the new one whenever the
+    // netrc file is rewritten.
+    match auth_method {
+        super::FlakeHubAuthSource::Netrc(path) => {
+            if let Some(token_provider) = token_provider {
+                tracing::info!(
+                    "Refreshing the FlakeHub token through {}",
+                    token_provider.name()
+                );
+
+                tokio::task::spawn(refresh_jwt_worker(
+                    path.to_path_buf(),
+                    flakehub_password.clone(),
+                    api.clone(),
+                    token_provider,
+                    probe_cache,
+                ));
+            } else {
+                tracing::warn!(
+                    "no way to get a new FlakeHub token -- netrc auth source will not be refreshed, so don't take too long..."
+                );
             }
         }
+        crate::FlakeHubAuthSource::DeterminateNixd => {
+            let api_clone = api.clone();
+            let netrc_file = PathBuf::from(DETERMINATE_NETRC_PATH);
+            let flakehub_api_server_clone = flakehub_api_server.clone();
+            let flakehub_cache_server_clone = flakehub_cache_server.clone();
 
-        let response = reqwest::Client::new()
-            .get(url.to_owned())
-            .header("User-Agent", USER_AGENT)
-            .basic_auth(flakehub_login, Some(&flakehub_password))
-            .send()
-            .await?;
-
-        if !response.status().is_success() {
-            return Err(Error::GetCacheName(
-                response.status(),
-                response.text().await?,
+            let initial_hash = hash_file(&netrc_file).await?;
+
+            tokio::task::spawn(refresh_determinate_token_worker(
+                netrc_file,
+                initial_hash,
+                flakehub_api_server_clone,
+                flakehub_cache_server_clone,
+                api_clone,
             ));
         }
+    }
+
+    let state = State {
+        substituter: flakehub_cache_server.to_owned(),
+        pushers: Arc::new(Pushers {
+            caches,
+            routes: push_options.routes,
+            paths_queued: AtomicUsize::new(0),
+        }),
+        closure_filter,
+        filtering: std::sync::Mutex::new(JoinSet::new()),
+    };
+
+    Ok(state)
+}
+
+/// Returns the name of the cache of a flake, or of the project that the
+/// token belongs to.
+async fn get_cache_name(
+    flakehub_api_server: &Url,
+    flake_name: Option<&str>,
+    flakehub_login: &str,
+    flakehub_password: &str,
+) -> Result<String> {
+    let mut url = flakehub_api_server
+        .join("project")
+        .map_err(|_| Error::Config(format!("bad URL '{flakehub_api_server}'")))?;
 
-        #[derive(Deserialize)]
-        struct ProjectInfo {
-            organization_uuid_v7: Uuid,
-            project_uuid_v7: Uuid,
+    if let Some(flakehub_flake_name) = flake_name {
+        if !flakehub_flake_name.is_empty() {
+            url = flakehub_api_server
+                .join(&format!("project/{flakehub_flake_name}"))
+                .map_err(|_| Error::Config(format!("bad URL '{flakehub_api_server}'")))?;
         }
+    }
 
-        let project_info = response.json::<ProjectInfo>().await?;
+    let response = reqwest::Client::new()
+        .get(url.to_owned())
+        .header("User-Agent", USER_AGENT)
+        .basic_auth(flakehub_login, Some(flakehub_password))
+        .send()
+        .await?;
 
-        format!(
-            "{}:{}",
-            project_info.organization_uuid_v7, project_info.project_uuid_v7,
-        )
-    };
+    if !response.status().is_success() {
+        return Err(Error::GetCacheName(
+            response.status(),
+            response.text().await?,
+        ));
+    }
+
+    #[derive(Deserialize)]
+    struct ProjectInfo {
+        organization_uuid_v7: Uuid,
+        project_uuid_v7: Uuid,
+    }
+
+    let project_info = response.json::<ProjectInfo>().await?;
 
-    tracing::info!("Using cache {:?}", cache_name);
+    Ok(format!(
+        "{}:{}",
+        project_info.organization_uuid_v7, project_info.project_uuid_v7,
+    ))
+}
 
-    let cache = unsafe { CacheName::new_unchecked(cache_name) };
+/// Checks whether FlakeHub rejects our token for pushing to the cache.
+///
+/// Querying for missing paths requires push access, so this asks about
+/// no paths at all. Anything other than a permission error is assumed to
+/// be transient.
+async fn token_rejected(api: &ApiClient, cache: &CacheName) -> bool {
+    match api.get_missing_paths(cache, Vec::new()).await {
+        Ok(_) => false,
+        Err(err) => {
+            let status = api_error_status(&err);
+            if status == Some(StatusCode::UNAUTHORIZED) || status == Some(StatusCode::FORBIDDEN) {
+                return true;
+            }
 
+            tracing::debug!(?err, "Failed to check whether we can push to FlakeHub");
+            false
+        }
+    }
+}
+
+async fn start_push_session(
+    store: Arc<NixStore>,
+    api: ApiClient,
+    cache: CacheName,
+    push_options: PushOptions,
+) -> Result<PushSession> {
     let cache_config = api.get_cache_config(&cache).await?;
 
+    tracing::debug!(?push_options, "Configuring the FlakeHub push session");
+
     let push_config = PushConfig {
-        num_workers: 5, // FIXME: use number of CPUs?
-        force_preamble: false,
+        num_workers: push_options.workers.max(1),
+        force_preamble: push_options.force_preamble,
     };
 
     let mp = indicatif::MultiProgress::new();
 
-    let push_session = Pusher::new(
-        store.clone(),
-        api.clone(),
-        cache.to_owned(),
-        cache_config,
-        mp,
-        push_config,
-    )
-    .into_push_session(PushSessionConfig {
-        no_closure: false,
-        ignore_upstream_cache_filter: false,
-    });
-
-    let state = State {
-        substituter: flakehub_cache_server.to_owned(),
-        push_session,
-    };
+    let push_session = Pusher::new(store, api, cache, cache_config, mp, push_config)
+        .into_push_session(PushSessionConfig {
+            no_closure: push_options.no_closure,
+            ignore_upstream_cache_filter: push_options.ignore_upstream_cache_filter,
+        });
 
-    Ok(state)
+    Ok(push_session)
 }
 
 #[derive(Debug)]
 struct NetrcInfo {
-    netrc: netrc_rs::Netrc,
     flakehub_cache_server_hostname: String,
     flakehub_login: String,
     flakehub_password: String,
@@ -231,118 +516,220 @@ async fn extract_info_from_netrc(
     flakehub_api_server: &Url,
     flakehub_cache_server: &Url,
 ) -> Result<NetrcInfo> {
-    let netrc = {
-        let mut netrc_file = File::open(netrc_path).await.map_err(|e| {
-            Error::Internal(format!("Failed to open {}: {}", netrc_path.display(), e))
-        })?;
-        let mut netrc_contents = String::new();
-        netrc_file
-            .read_to_string(&mut netrc_contents)
-            .await
-            .map_err(|e| {
-                Error::Internal(format!(
-                    "Failed to read {} contents: {}",
-                    netrc_path.display(),
-                    e
-                ))
-            })?;
-        netrc_rs::Netrc::parse(netrc_contents, false).map_err(Error::Netrc)?
-    };
+    let netrc_contents = tokio::fs::read_to_string(netrc_path)
+        .await
+        .map_err(|e| Error::Io(e, format!("Reading {}", netrc_path.display())))?;
+    let netrc = crate::netrc::Netrc::parse(netrc_contents);
 
-    let flakehub_netrc_entry = netrc
-        .machines
-        .iter()
-        .find(|machine| {
-            machine.name.as_ref() == flakehub_api_server.host().map(|x| x.to_string()).as_ref()
-        })
-        .ok_or_else(|| Error::MissingCreds(flakehub_api_server.to_string()))?
-        .to_owned();
+    let flakehub_api_server_hostname = flakehub_api_server
+        .host()
+        .ok_or_else(|| Error::BadUrl(flakehub_api_server.to_owned()))?
+        .to_string();
+    if !netrc.has_machine(&flakehub_api_server_hostname) {
+        return Err(Error::MissingCreds(flakehub_api_server.to_string()));
+    }
 
     let flakehub_cache_server_hostname = flakehub_cache_server
         .host()
         .ok_or_else(|| Error::BadUrl(flakehub_cache_server.to_owned()))?
         .to_string();
-    let flakehub_login = flakehub_netrc_entry.login.ok_or_else(|| {
-        Error::Config(format!(
-            "netrc file does not contain a login for '{flakehub_api_server}'"
-        ))
-    })?;
-    let flakehub_password = flakehub_netrc_entry.password.ok_or_else(|| {
-        Error::Config(format!(
-            "netrc file does not contain a password for '{flakehub_api_server}'"
-        ))
-    })?;
+    let flakehub_login = netrc
+        .login(&flakehub_api_server_hostname)
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "netrc file does not contain a login for '{flakehub_api_server}'"
+            ))
+        })?
+        .to_owned();
+    let flakehub_password = netrc
+        .password(&flakehub_api_server_hostname)
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "netrc file does not contain a password for '{flakehub_api_server}'"
+            ))
+        })?
+        .to_owned();
 
     Ok(NetrcInfo {
-        netrc,
         flakehub_cache_server_hostname,
         flakehub_login,
         flakehub_password,
     })
 }
 
-pub async fn enqueue_paths(state: &State, store_paths: Vec<StorePath>) -> Result<()> {
-    state.push_session.queue_many(store_paths)?;
+/// Queues paths for pushing.
+///
+/// With an upload policy, the paths are filtered in the background, and
+/// then queued.
+pub fn enqueue_paths(state: &State, store_paths: Vec<StorePath>) -> Result<()> {
+    if state.is_read_only() {
+        return Ok(());
+    }
+
+    let Some(closure_filter) = &state.closure_filter else {
+        return state.pushers.queue(store_paths);
+    };
+
+    let closure_filter = closure_filter.clone();
+    let pushers = state.pushers.clone();
+
+    state
+        .filtering
+        .lock()
+        .expect("filtering lock poisoned")
+        .spawn(async move {
+            let res = match closure_filter.filter(store_paths).await {
+                Ok(paths) => pushers.queue(paths),
+                Err(err) => Err(err),
+            };
+
+            if let Err(err) = res {
+                tracing::error!("Failed to queue paths for the FlakeHub Cache: {}", err);
+            }
+        });
 
     Ok(())
 }
 
-/// Refresh the GitHub Actions JWT every 2 minutes (slightly less than half of the default validity
-/// period) to ensure pushing / pulling doesn't stop working.
+impl Pushers {
+    /// Hands paths to the push sessions of the caches they're routed to.
+    fn queue(&self, store_paths: Vec<StorePath>) -> Result<()> {
+        let num_paths = store_paths.len();
+
+        let mut paths_per_cache = vec![Vec::new(); self.caches.len()];
+        for path in store_paths {
+            let name = path.name();
+            let routed: Vec<&str> = self
+                .routes
+                .iter()
+                .filter(|route| route.pattern.matches(&name))
+                .map(|route| route.flake_name.as_str())
+                .collect();
+
+            for (cache, paths) in self.caches.iter().zip(&mut paths_per_cache) {
+                let is_routed = cache
+                    .flake_name
+                    .as_deref()
+                    .is_some_and(|flake_name| routed.contains(&flake_name));
+
+                if routed.is_empty() || is_routed {
+                    paths.push(path.clone());
+                }
+            }
+        }
+
+        for (cache, paths) in self.caches.iter().zip(paths_per_cache) {
+            if let Some(push_session) = &cache.push_session {
+                if !paths.is_empty() {
+                    push_session.queue_many(paths)?;
+                }
+            }
+        }
+
+        self.paths_queued.fetch_add(num_paths, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+/// The outcome of the pushes.
+#[derive(Debug, Default)]
+pub struct PushReport {
+    /// The result for each path.
+    ///
+    /// A path that was pushed to several caches only succeeds if it was
+    /// pushed to all of them.
+    pub paths: HashMap<StorePath, anyhow::Result<()>>,
+
+    /// Why push sessions failed as a whole. Their paths are missing from
+    /// `paths`, since there is no way to tell which of them were pushed.
+    pub session_errors: Vec<String>,
+}
+
+/// Waits for the pushes to finish.
+///
+/// A failed push session doesn't affect the results of the others.
+pub async fn wait(state: State) -> Result<PushReport> {
+    let mut filtering = state
+        .filtering
+        .into_inner()
+        .expect("filtering lock poisoned");
+    while filtering.join_next().await.is_some() {}
+
+    let pushers = Arc::into_inner(state.pushers)
+        .ok_or_else(|| Error::Internal("FlakeHub push sessions are still in use".to_owned()))?;
+
+    let sessions = pushers.caches.into_iter().filter_map(|cache| {
+        let description = cache.describe().to_owned();
+        cache
+            .push_session
+            .map(|push_session| async move { (description, push_session.wait().await) })
+    });
+
+    let mut report = PushReport::default();
+
+    for (description, res) in futures::future::join_all(sessions).await {
+        let paths = match res {
+            Ok(paths) => paths,
+            Err(err) => {
+                report
+                    .session_errors
+                    .push(format!("pushing to {description}: {err}"));
+                continue;
+            }
+        };
+
+        for (path, res) in paths {
+            let res = res.with_context(|| format!("pushing to {description}"));
+            let entry = report.paths.entry(path).or_insert(Ok(()));
+            if entry.is_ok() {
+                *entry = res;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Refresh the JWT halfway through its lifetime to ensure pushing / pulling doesn't stop working,
+/// or right away if pushing to `probe_cache` is rejected.
 #[tracing::instrument(skip_all)]
-async fn refresh_github_actions_jwt_worker(
+async fn refresh_jwt_worker(
     netrc_path: std::path::PathBuf,
-    mut github_jwt: String,
+    mut jwt: String,
     api: ApiClient,
+    token_provider: Box<dyn TokenProvider>,
+    probe_cache: Option<CacheName>,
 ) -> Result<()> {
     // NOTE(cole-h): This is a workaround -- at the time of writing, GitHub Actions JWTs are only
     // valid for 5 minutes after being issued. FlakeHub uses these JWTs for authentication, which
     // means that after those 5 minutes have passed and the token is expired, FlakeHub (and by
     // extension FlakeHub Cache) will no longer allow requests using this token. However, GitHub
     // gives us a way to repeatedly request new tokens, so we utilize that and refresh the token
-    // every 2 minutes (less than half of the lifetime of the token).
-
-    // TODO(cole-h): this should probably be half of the token's lifetime ((exp - iat) / 2), but
-    // getting this is nontrivial so I'm not going to do it until GitHub changes the lifetime and
-    // breaks this.
-    let next_refresh = std::time::Duration::from_secs(2 * 60);
-
-    // NOTE(cole-h): we sleep until the next refresh at first because we already got a token from
-    // GitHub recently, don't need to try again until we actually might need to get a new one.
-    tokio::time::sleep(next_refresh).await;
-
-    // NOTE(cole-h): https://docs.github.com/en/actions/deployment/security-hardening-your-deployments/configuring-openid-connect-in-cloud-providers#requesting-the-jwt-using-environment-variables
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert(
-        reqwest::header::ACCEPT,
-        HeaderValue::from_static("application/json;api-version=2.0"),
-    );
-    headers.insert(
-        reqwest::header::CONTENT_TYPE,
-        HeaderValue::from_static("application/json"),
-    );
-
-    let github_client = reqwest::Client::builder()
-        .user_agent(USER_AGENT)
-        .default_headers(headers)
-        .build()?;
+    // once half of its lifetime has passed. Other CI systems work the same way.
+
+    // NOTE(cole-h): we sleep until the next refresh at first because we already got a token
+    // recently, don't need to try again until we actually might need to get a new one.
+    wait_for_refresh(next_refresh_for(&jwt), &api, probe_cache.as_ref()).await;
 
     loop {
-        match rewrite_github_actions_token(&github_client, &netrc_path, &github_jwt).await {
-            Ok(new_github_jwt) => {
-                github_jwt = new_github_jwt;
+        match rewrite_token(&*token_provider, &netrc_path, &jwt).await {
+            Ok(new_jwt) => {
+                jwt = new_jwt;
 
-                api.set_token(&github_jwt)?;
+                api.set_token(&jwt)?;
 
+                let next_refresh = next_refresh_for(&jwt);
                 tracing::debug!(
                     "Stored new token in netrc and API client, sleeping for {next_refresh:?}"
                 );
-                tokio::time::sleep(next_refresh).await;
+                wait_for_refresh(next_refresh, &api, probe_cache.as_ref()).await;
             }
             Err(e) => {
                 tracing::error!(
                     ?e,
-                    "Failed to get a new JWT from GitHub, trying again in 10 seconds"
+                    "Failed to get a new JWT from {}, trying again in 10 seconds",
+                    token_provider.name()
                 );
                 tokio::time::sleep(std::time::Duration::from_secs(10)).await;
             }
@@ -350,70 +737,104 @@ async fn refresh_github_actions_jwt_worker(
     }
 }
 
-#[tracing::instrument(skip_all)]
-async fn rewrite_github_actions_token(
-    client: &reqwest::Client,
-    netrc_path: &Path,
-    old_github_jwt: &str,
-) -> Result<String> {
-    // NOTE(cole-h): https://docs.github.com/en/actions/deployment/security-hardening-your-deployments/configuring-openid-connect-in-cloud-providers#requesting-the-jwt-using-environment-variables
-    let runtime_token = std::env::var("ACTIONS_ID_TOKEN_REQUEST_TOKEN").map_err(|e| {
-        Error::Internal(format!(
-            "ACTIONS_ID_TOKEN_REQUEST_TOKEN was invalid unicode: {e}"
-        ))
-    })?;
-    let runtime_url = std::env::var("ACTIONS_ID_TOKEN_REQUEST_URL").map_err(|e| {
-        Error::Internal(format!(
-            "ACTIONS_ID_TOKEN_REQUEST_URL was invalid unicode: {e}"
-        ))
-    })?;
+/// Sleeps for `duration`, returning early if FlakeHub starts rejecting the
+/// token for pushing to `probe_cache`.
+///
+/// The push sessions don't tell us when their requests are rejected, so
+/// this checks every [`TOKEN_PROBE_INTERVAL`].
+async fn wait_for_refresh(duration: Duration, api: &ApiClient, probe_cache: Option<&CacheName>) {
+    let deadline = tokio::time::Instant::now() + duration;
 
-    let token_request_url = format!("{runtime_url}&audience=api.flakehub.com");
-    let token_response = client
-        .request(reqwest::Method::GET, &token_request_url)
-        .bearer_auth(runtime_token)
-        .send()
-        .await
-        .with_context(|| format!("sending request to {token_request_url}"))?;
+    let Some(cache) = probe_cache else {
+        tokio::time::sleep_until(deadline).await;
+        return;
+    };
 
-    if let Err(e) = token_response.error_for_status_ref() {
-        tracing::error!(?e, "Got error response when requesting token");
-        return Err(e)?;
-    }
+    loop {
+        let now = tokio::time::Instant::now();
+        if now >= deadline {
+            return;
+        }
+
+        tokio::time::sleep((deadline - now).min(TOKEN_PROBE_INTERVAL)).await;
 
-    #[derive(serde::Deserialize)]
-    struct TokenResponse {
-        value: String,
+        if tokio::time::Instant::now() < deadline && token_rejected(api, cache).await {
+            tracing::info!("FlakeHub rejected our token, refreshing it ahead of schedule");
+            return;
+        }
     }
+}
 
-    let token_response: TokenResponse = token_response
-        .json()
-        .await
-        .with_context(|| "converting response into json")?;
+/// Returns how long to wait before refreshing `jwt`, which is until half of
+/// its lifetime has passed.
+fn next_refresh_for(jwt: &str) -> Duration {
+    let Some(JwtLifetime { iat, exp }) = decode_jwt_lifetime(jwt) else {
+        tracing::debug!("Couldn't decode the lifetime of the JWT, assuming the default");
+        return DEFAULT_REFRESH_INTERVAL;
+    };
 
-    let new_github_jwt_string = token_response.value;
-    let netrc_contents = tokio::fs::read_to_string(netrc_path)
-        .await
-        .with_context(|| format!("failed to read {netrc_path:?} to string"))?;
-    let new_netrc_contents = netrc_contents.replace(old_github_jwt, &new_github_jwt_string);
+    let refresh_at =
+        SystemTime::UNIX_EPOCH + Duration::from_secs(iat + exp.saturating_sub(iat) / 2);
 
-    // NOTE(cole-h): create the temporary file right next to the real one so we don't run into
-    // cross-device linking issues when renaming
-    let netrc_path_tmp = netrc_path.with_extension("tmp");
-    tokio::fs::write(&netrc_path_tmp, new_netrc_contents)
-        .await
-        .with_context(|| format!("writing new JWT to {netrc_path_tmp:?}"))?;
-    tokio::fs::rename(&netrc_path_tmp, &netrc_path)
-        .await
-        .with_context(|| format!("renaming {netrc_path_tmp:?} to {netrc_path:?}"))?;
+    refresh_at
+        .duration_since(SystemTime::now())
+        .unwrap_or_default()
+        .clamp(MIN_REFRESH_INTERVAL, MAX_REFRESH_INTERVAL)
+}
+
+/// The claims of a JWT that determine when it expires.
+#[derive(Debug, Deserialize)]
+struct JwtLifetime {
+    /// When the token was issued, in seconds since the epoch.
+    iat: u64,
+
+    /// When the token expires, in seconds since the epoch.
+    exp: u64,
+}
+
+/// Decodes the lifetime of a JWT, without verifying it.
+fn decode_jwt_lifetime(jwt: &str) -> Option<JwtLifetime> {
+    let payload = jwt.split('.').nth(1)?;
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
 
-    Ok(new_github_jwt_string)
+    serde_json::from_slice(&payload).ok()
+}
+
+/// Returns the HTTP status of an error from the FlakeHub Cache, if it has one.
+fn api_error_status(err: &anyhow::Error) -> Option<StatusCode> {
+    err.chain()
+        .find_map(|cause| match cause.downcast_ref::<ApiError>()? {
+            ApiError::Structured(e) => StatusCode::from_u16(e.code).ok(),
+            ApiError::Unstructured(status, _) => Some(*status),
+        })
+}
+
+#[tracing::instrument(skip_all)]
+async fn rewrite_token(
+    token_provider: &dyn TokenProvider,
+    netrc_path: &Path,
+    old_jwt: &str,
+) -> Result<String> {
+    let new_jwt = token_provider.fetch_token().await?;
+
+    let old_jwt = old_jwt.to_owned();
+    let new_jwt_clone = new_jwt.clone();
+    crate::netrc::edit(netrc_path, move |netrc| {
+        if netrc.replace_password(&old_jwt, &new_jwt_clone) == 0 {
+            tracing::warn!("No netrc entries use the old JWT, not storing the new one");
+        }
+    })
+    .await?;
+
+    Ok(new_jwt)
 }
 
 #[tracing::instrument(skip_all)]
 async fn refresh_determinate_token_worker(
     netrc_file: PathBuf,
-    mut inode: u64,
+    mut netrc_hash: [u8; 32],
     flakehub_api_server: Url,
     flakehub_cache_server: Url,
     api: ApiClient,
@@ -423,26 +844,27 @@ async fn refresh_determinate_token_worker(
     // moment, it does it roughly every 2 minutes (less than half of the total lifetime of the
     // issued token).
 
-    loop {
-        tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+    let mut watcher = FileWatcher::new(&netrc_file);
 
-        let meta = tokio::fs::metadata(&netrc_file)
-            .await
-            .map_err(|e| Error::Io(e, format!("getting metadata of {}", netrc_file.display())));
+    loop {
+        watcher.changed().await;
 
-        let Ok(meta) = meta else {
-            tracing::error!(e = ?meta);
-            continue;
+        // The file may be rewritten in place, or replaced by a file that
+        // happens to reuse the inode, so compare the contents.
+        let current_hash = match hash_file(&netrc_file).await {
+            Ok(current_hash) => current_hash,
+            Err(e) => {
+                tracing::error!(?e);
+                continue;
+            }
         };
 
-        let current_inode = meta.ino();
-
-        if current_inode == inode {
-            tracing::debug!("current inode is the same, file didn't change");
+        if current_hash == netrc_hash {
+            tracing::debug!("contents are the same, file didn't change");
             continue;
         }
 
-        tracing::debug!("current inode is different, file changed");
+        tracing::debug!("contents are different, file changed");
 
         let flakehub_password = match extract_info_from_netrc(
             &netrc_file,
@@ -463,8 +885,8 @@ async fn refresh_determinate_token_worker(
 
         match api.set_token(&flakehub_password) {
             Ok(_) => {
-                inode = current_inode;
-                tracing::debug!("successfully set new auth token, recorded new inode");
+                netrc_hash = current_hash;
+                tracing::debug!("successfully set new auth token, recorded new hash");
             }
             Err(e) => {
                 tracing::warn!(?e, "Failed to update auth token");
@@ -472,6 +894,162 @@ async fn refresh_determinate_token_worker(
             }
         }
 
-        tracing::debug!("Stored new token in API client, sleeping for 30s");
+        tracing::debug!("Stored new token in API client");
+    }
+}
+
+/// Returns the SHA-256 hash of the contents of a file.
+async fn hash_file(path: &Path) -> Result<[u8; 32]> {
+    let contents = tokio::fs::read(path)
+        .await
+        .map_err(|e| Error::Io(e, format!("reading {}", path.display())))?;
+
+    Ok(Sha256::digest(&contents).into())
+}
+
+/// Reports when a file may have changed.
+///
+/// Uses inotify (or the platform's equivalent) where possible, and falls
+/// back to polling.
+struct FileWatcher {
+    /// Signals events that affect the file, as long as the watch is alive.
+    watch: Option<(notify::RecommendedWatcher, UnboundedReceiver<()>)>,
+}
+
+impl FileWatcher {
+    fn new(path: &Path) -> Self {
+        let watch = watch_file(path)
+            .map_err(|e| {
+                tracing::warn!(
+                    "Failed to watch {}, polling it instead: {}",
+                    path.display(),
+                    e
+                );
+            })
+            .ok();
+
+        Self { watch }
+    }
+
+    /// Waits until the file may have changed.
+    async fn changed(&mut self) {
+        let Some((_, changes)) = &mut self.watch else {
+            tokio::time::sleep(NETRC_POLL_INTERVAL).await;
+            return;
+        };
+
+        if changes.recv().await.is_none() {
+            tracing::warn!("Stopped watching the netrc file, polling it instead");
+            self.watch = None;
+            return;
+        }
+
+        // Writing a file usually causes a burst of events, so let them
+        // settle and handle them together.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        while changes.try_recv().is_ok() {}
+    }
+}
+
+fn watch_file(path: &Path) -> notify::Result<(notify::RecommendedWatcher, UnboundedReceiver<()>)> {
+    use notify::Watcher;
+
+    let (tx, rx) = unbounded_channel();
+    let target = path.to_owned();
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let relevant = match event {
+            Ok(event) => !event.kind.is_access() && event.paths.contains(&target),
+            Err(e) => {
+                // We may have missed an event, so check the file anyway.
+                tracing::debug!("Error watching {}: {}", target.display(), e);
+                true
+            }
+        };
+
+        if relevant {
+            let _ = tx.send(());
+        }
+    })?;
+
+    // Watch the directory rather than the file itself, since the file is
+    // usually replaced by renaming a new file over it.
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+
+    Ok((watcher, rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jwt(claims: serde_json::Value) -> String {
+        let encode = |s: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(s);
+        format!(
+            "{}.{}.signature",
+            encode(r#"{"alg":"RS256"}"#),
+            encode(&claims.to_string())
+        )
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn refreshes_halfway_through_the_lifetime() {
+        let now = now();
+        let next_refresh = next_refresh_for(&jwt(serde_json::json!({
+            "iat": now,
+            "exp": now + 600,
+        })));
+
+        assert!(next_refresh <= Duration::from_secs(300));
+        assert!(next_refresh >= Duration::from_secs(290));
+    }
+
+    #[test]
+    fn refresh_interval_is_clamped() {
+        let now = now();
+
+        let expired = jwt(serde_json::json!({ "iat": now - 600, "exp": now - 300 }));
+        assert_eq!(next_refresh_for(&expired), MIN_REFRESH_INTERVAL);
+
+        let long_lived = jwt(serde_json::json!({ "iat": now, "exp": now + 24 * 60 * 60 }));
+        assert_eq!(next_refresh_for(&long_lived), MAX_REFRESH_INTERVAL);
+    }
+
+    #[test]
+    fn bad_tokens_use_the_default_interval() {
+        let encode = |s: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(s);
+
+        for token in [
+            String::new(),
+            "not-a-jwt".to_owned(),
+            "header.!!!.signature".to_owned(),
+            format!("header.{}.signature", encode("not json")),
+            jwt(serde_json::json!({ "iat": 1 })),
+            jwt(serde_json::json!({ "iat": "now", "exp": "later" })),
+        ] {
+            assert!(decode_jwt_lifetime(&token).is_none(), "{token:?}");
+            assert_eq!(next_refresh_for(&token), DEFAULT_REFRESH_INTERVAL);
+        }
+    }
+
+    #[test]
+    fn padded_payloads_are_decoded() {
+        let payload = base64::engine::general_purpose::URL_SAFE
+            .encode(serde_json::json!({ "iat": 1, "exp": 2 }).to_string());
+        assert!(payload.ends_with('='));
+
+        let lifetime = decode_jwt_lifetime(&format!("header.{payload}.signature")).unwrap();
+        assert_eq!((lifetime.iat, lifetime.exp), (1, 2));
     }
 }
@@ -1,38 +1,158 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
+use crate::compression::NarCompression;
+use crate::derivation;
 use crate::error::{Error, Result};
-use crate::narinfo::{Compression, NarInfo};
+use crate::journal::Journal;
+use crate::namespace::CacheNamespace;
+use crate::narinfo::NarInfo;
+use crate::policy::{Policy, SkippedPaths};
 use crate::telemetry;
-use async_compression::tokio::bufread::ZstdEncoder;
+use crate::util::{self, HashingReader};
+use crate::UploadScope;
+use attic::hash::Hash;
 use attic::nix_store::{NixStore, StorePath, ValidPathInfo};
-use futures::stream::TryStreamExt;
+use futures::stream::{StreamExt, TryStreamExt};
 use gha_cache::{Api, Credentials};
+use serde::Serialize;
 use tokio::sync::{
     mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
     RwLock,
 };
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::Instant;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 
+/// The number of cache lookups to perform at the same time.
+const MAX_CACHE_LOOKUPS: usize = 16;
+
 pub struct GhaCache {
     /// The GitHub Actions Cache API.
     pub api: Arc<Api>,
 
     /// The future from the completion of the worker.
-    worker_result: RwLock<Option<tokio::task::JoinHandle<Result<()>>>>,
+    worker_result: RwLock<Option<tokio::task::JoinHandle<Result<UploadReport>>>>,
 
     channel_tx: UnboundedSender<Request>,
+
+    /// The progress of the upload worker.
+    progress: Arc<Progress>,
 }
 
 #[derive(Debug)]
 enum Request {
-    Shutdown,
-    Upload(StorePath),
+    Shutdown {
+        /// When to give up on the uploads that haven't finished yet.
+        deadline: Option<Instant>,
+    },
+    Enqueue {
+        store_paths: Vec<StorePath>,
+
+        /// The derivation that produced `store_paths`, if known.
+        deriver: Option<PathBuf>,
+    },
+}
+
+/// A path to upload.
+#[derive(Debug)]
+struct QueuedPath {
+    path: StorePath,
+
+    /// The references of the path, excluding itself.
+    references: Vec<StorePath>,
+
+    /// The size of the uncompressed NAR.
+    nar_size: u64,
+
+    /// The derivation that produced the path, if known.
+    deriver: Option<PathBuf>,
+}
+
+/// Options that control what gets uploaded, and how.
+#[derive(Debug, Clone)]
+pub struct UploadOptions {
+    /// The maximum number of compressed bytes to upload.
+    pub max_upload_bytes: Option<u64>,
+
+    /// The number of paths to upload at the same time.
+    pub concurrency: usize,
+
+    /// Which store paths to upload.
+    pub scope: UploadScope,
+
+    /// The upstream cache.
+    pub upstream: Option<String>,
+
+    /// The compression to apply to NARs.
+    pub compression: NarCompression,
+
+    /// Which store paths may be uploaded.
+    pub policy: Arc<Policy>,
+
+    /// Counts the paths that `policy` skips.
+    pub skipped_paths: Arc<SkippedPaths>,
+
+    /// The journal of enqueued and uploaded paths, if enabled.
+    pub journal: Option<Arc<Journal>>,
+}
+
+/// Live counters of the upload worker.
+#[derive(Debug, Default)]
+struct Progress {
+    queued: AtomicUsize,
+    in_flight: AtomicUsize,
+    completed: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+/// A snapshot of the upload progress.
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadStatus {
+    /// Paths that are waiting to be uploaded.
+    pub queued: usize,
+
+    /// Paths that are being uploaded.
+    pub in_flight: usize,
+
+    /// Paths that have been uploaded.
+    pub completed: usize,
+
+    /// Paths that failed to upload, or weren't attempted because a
+    /// reference wasn't uploaded or GitHub rate-limited us.
+    pub failed: usize,
+
+    /// Whether GitHub has rate-limited us, which stops all uploads.
+    pub circuit_breaker_tripped: bool,
+}
+
+/// A summary of the work done by the upload worker.
+#[derive(Debug, Default)]
+pub struct UploadReport {
+    /// Paths that were not uploaded because the upload budget was exhausted.
+    pub skipped_over_budget: Vec<StorePath>,
+
+    /// Paths that were not uploaded before the shutdown deadline.
+    pub abandoned: Vec<StorePath>,
+
+    /// Paths that weren't uploaded, with the reason: the error of the last
+    /// attempt if the upload failed even after being retried, or why it
+    /// wasn't attempted (rate-limiting or a reference that wasn't
+    /// uploaded).
+    pub failed: Vec<(StorePath, String)>,
 }
 
 impl GhaCache {
     pub fn new(
         credentials: Credentials,
-        cache_version: Option<String>,
+        namespace: &CacheNamespace,
+        options: UploadOptions,
         store: Arc<NixStore>,
         metrics: Arc<telemetry::TelemetryReport>,
         narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
@@ -47,9 +167,7 @@ impl GhaCache {
             })),
         )?;
 
-        if let Some(cache_version) = &cache_version {
-            api.mutate_version(cache_version.as_bytes());
-        }
+        api.mutate_version(namespace.to_string().as_bytes());
 
         let (channel_tx, channel_rx) = unbounded_channel();
 
@@ -57,11 +175,16 @@ impl GhaCache {
 
         let api2 = api.clone();
 
+        let progress = Arc::new(Progress::default());
+        let progress2 = progress.clone();
+
         let worker_result = tokio::task::spawn(async move {
             worker(
-                &api2,
+                api2,
                 store,
                 channel_rx,
+                options,
+                progress2,
                 metrics,
                 narinfo_negative_cache.clone(),
             )
@@ -72,138 +195,849 @@ impl GhaCache {
             api,
             worker_result: RwLock::new(Some(worker_result)),
             channel_tx,
+            progress,
         })
     }
 
-    pub async fn shutdown(&self) -> Result<()> {
+    /// Returns the current upload progress.
+    pub fn status(&self) -> UploadStatus {
+        UploadStatus {
+            queued: self.progress.queued.load(Ordering::Relaxed),
+            in_flight: self.progress.in_flight.load(Ordering::Relaxed),
+            completed: self.progress.completed.load(Ordering::Relaxed),
+            failed: self.progress.failed.load(Ordering::Relaxed),
+            circuit_breaker_tripped: self.api.circuit_breaker_tripped(),
+        }
+    }
+
+    /// Waits for the queued uploads to finish, or until `deadline`.
+    pub async fn shutdown(&self, deadline: Option<Instant>) -> Result<UploadReport> {
         if let Some(worker_result) = self.worker_result.write().await.take() {
             self.channel_tx
-                .send(Request::Shutdown)
+                .send(Request::Shutdown { deadline })
                 .expect("Cannot send shutdown message");
             worker_result
                 .await
                 .expect("failed to read result from gha worker")
         } else {
-            Ok(())
+            Ok(UploadReport::default())
         }
     }
 
-    pub async fn enqueue_paths(
+    /// Queues the given paths for uploading, according to the upload scope.
+    ///
+    /// `deriver` is the derivation that produced `store_paths`, if known.
+    /// The worker works out the rest, so that this returns right away.
+    pub fn enqueue_paths(
         &self,
-        store: Arc<NixStore>,
         store_paths: Vec<StorePath>,
+        deriver: Option<PathBuf>,
     ) -> Result<()> {
-        // FIXME: make sending the closure optional. We might want to
-        // only send the paths that have been built by the user, under
-        // the assumption that everything else is already in a binary
-        // cache.
-        // FIXME: compute_fs_closure_multi doesn't return a
-        // toposort, though it doesn't really matter for the GHA
-        // cache.
-        let closure = store
-            .compute_fs_closure_multi(store_paths, false, false, false)
-            .await?;
-
-        for p in closure {
-            self.channel_tx
-                .send(Request::Upload(p))
-                .map_err(|_| Error::Internal("Cannot send upload message".to_owned()))?;
+        self.channel_tx
+            .send(Request::Enqueue {
+                store_paths,
+                deriver,
+            })
+            .map_err(|_| Error::Internal("Cannot send upload message".to_owned()))
+    }
+}
+
+/// Works out which paths to upload for the paths that were enqueued.
+struct Resolver {
+    api: Arc<Api>,
+    store: Arc<NixStore>,
+
+    /// Which store paths to upload.
+    scope: UploadScope,
+
+    /// The upstream cache, which is checked for paths in the
+    /// `outputs-plus-missing` scope.
+    upstream: Option<String>,
+
+    /// The HTTP client for requests to the upstream cache.
+    http_client: reqwest::Client,
+
+    /// Which store paths may be uploaded.
+    policy: Arc<Policy>,
+
+    /// Counts the paths that `policy` skips.
+    skipped_paths: Arc<SkippedPaths>,
+
+    /// The hashes of the narinfos that are known to be missing from the
+    /// GitHub Actions Cache.
+    narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
+
+    /// Paths that were handed to the scheduler, which don't need to be
+    /// resolved again.
+    resolved: std::sync::Mutex<HashSet<StorePath>>,
+
+    /// Whether paths were found in a cache, so that the closures of
+    /// several enqueued paths don't look up the paths they share again.
+    lookups: std::sync::Mutex<HashMap<StorePath, bool>>,
+}
+
+impl Resolver {
+    /// Returns the paths to upload, with their references.
+    async fn resolve(
+        &self,
+        store_paths: Vec<StorePath>,
+        deriver: Option<PathBuf>,
+    ) -> Result<Vec<QueuedPath>> {
+        let outputs: HashSet<StorePath> = if deriver.is_some() {
+            store_paths.iter().cloned().collect()
+        } else {
+            HashSet::new()
+        };
+
+        let closure = self.closure(store_paths).await?;
+        let path_infos = util::query_path_infos(&self.store, closure).await?;
+        let path_infos =
+            self.policy
+                .filter_closure(&self.store, path_infos, &self.skipped_paths)?;
+
+        let mut queued = Vec::with_capacity(path_infos.len());
+        for (path, path_info) in path_infos {
+            let references = path_info
+                .references
+                .iter()
+                .map(|r| self.store.parse_store_path(r))
+                .filter(|r| r.as_ref().map_or(true, |r| *r != path))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+
+            let deriver = deriver.clone().filter(|_| outputs.contains(&path));
+
+            queued.push(QueuedPath {
+                path,
+                references,
+                nar_size: path_info.nar_size,
+                deriver,
+            });
+        }
+
+        self.resolved
+            .lock()
+            .expect("resolved lock poisoned")
+            .extend(queued.iter().map(|queued| queued.path.clone()));
+
+        Ok(queued)
+    }
+
+    /// Returns the paths in the upload scope of `store_paths` that haven't
+    /// been resolved before.
+    async fn closure(&self, store_paths: Vec<StorePath>) -> Result<Vec<StorePath>> {
+        let requested: HashSet<StorePath> = store_paths.iter().cloned().collect();
+
+        let closure = match self.scope {
+            UploadScope::Outputs => store_paths,
+            UploadScope::Closure | UploadScope::OutputsPlusMissing => {
+                self.store
+                    .compute_fs_closure_multi(store_paths, false, false, false)
+                    .await?
+            }
+        };
+
+        let closure: Vec<StorePath> = {
+            let resolved = self.resolved.lock().expect("resolved lock poisoned");
+            closure
+                .into_iter()
+                .filter(|path| !resolved.contains(path))
+                .collect()
+        };
+
+        if self.scope != UploadScope::OutputsPlusMissing {
+            return Ok(closure);
+        }
+
+        let candidates: Vec<StorePath> = closure
+            .iter()
+            .filter(|path| !requested.contains(*path))
+            .cloned()
+            .collect();
+
+        let mut cached = HashSet::new();
+        let mut lookups = futures::stream::iter(candidates)
+            .map(|path| async move {
+                let is_cached = self.is_cached(&path).await;
+                (path, is_cached)
+            })
+            .buffer_unordered(MAX_CACHE_LOOKUPS);
+        while let Some((path, is_cached)) = lookups.next().await {
+            if is_cached {
+                cached.insert(path);
+            }
+        }
+
+        Ok(closure
+            .into_iter()
+            .filter(|path| !cached.contains(path))
+            .collect())
+    }
+
+    /// Returns whether a path is already in the GitHub Actions Cache or
+    /// the upstream cache.
+    ///
+    /// Lookup failures are treated as misses, so that the path is uploaded.
+    /// Results are remembered.
+    async fn is_cached(&self, path: &StorePath) -> bool {
+        if let Some(&is_cached) = self
+            .lookups
+            .lock()
+            .expect("lookups lock poisoned")
+            .get(path)
+        {
+            return is_cached;
+        }
+
+        let is_cached = self.look_up(path).await;
+
+        self.lookups
+            .lock()
+            .expect("lookups lock poisoned")
+            .insert(path.clone(), is_cached);
+
+        is_cached
+    }
+
+    async fn look_up(&self, path: &StorePath) -> bool {
+        let hash = path.to_hash().as_str().to_owned();
+        let narinfo_path = format!("{hash}.narinfo");
+
+        if !self.narinfo_negative_cache.read().await.contains(&hash) {
+            match self.api.get_file_url(&[&narinfo_path]).await {
+                Ok(Some(_)) => return true,
+                Ok(None) => {
+                    self.narinfo_negative_cache.write().await.insert(hash);
+                }
+                Err(err) => {
+                    tracing::debug!("Failed to look up '{}': {}", narinfo_path, err);
+                }
+            }
+        }
+
+        let Some(upstream) = &self.upstream else {
+            return false;
+        };
+
+        match self
+            .http_client
+            .head(format!("{upstream}/{narinfo_path}"))
+            .send()
+            .await
+        {
+            Ok(res) => res.status().is_success(),
+            Err(err) => {
+                tracing::debug!("Failed to look up '{}' upstream: {}", narinfo_path, err);
+                false
+            }
+        }
+    }
+}
+
+/// Tracks which paths can be uploaded.
+///
+/// A path is only uploaded once all of its references have been
+/// uploaded, so that the cache never contains a narinfo with dangling
+/// references. If a reference can't be uploaded, neither can the path.
+/// References that were never queued are assumed to be available
+/// elsewhere.
+///
+/// Among the paths that can be uploaded, the smallest go first, so that
+/// as many paths as possible make it into the cache if the uploads are
+/// cut short.
+#[derive(Default)]
+struct Scheduler {
+    /// The state of every path that has been queued.
+    states: HashMap<StorePath, PathState>,
+
+    /// Paths whose references are in the cache, by NAR size and queue
+    /// order.
+    ready: BTreeMap<(u64, u64), StorePath>,
+
+    /// Paths that are waiting for their references.
+    waiting: HashMap<StorePath, WaitingPath>,
+
+    /// The waiting paths that reference each path that isn't in the cache
+    /// yet.
+    dependents: HashMap<StorePath, Vec<StorePath>>,
+
+    /// Paths that were given up on because a reference wasn't uploaded,
+    /// with that reference.
+    dropped: Vec<(StorePath, StorePath)>,
+
+    /// The number of paths that have been queued.
+    num_queued: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PathState {
+    /// Waiting for its references, ready, or being uploaded.
+    Pending,
+
+    /// In the cache.
+    Uploaded,
+
+    /// Didn't make it into the cache.
+    Failed,
+}
+
+struct WaitingPath {
+    /// The number of references that aren't in the cache yet.
+    remaining: usize,
+    nar_size: u64,
+    seq: u64,
+}
+
+impl Scheduler {
+    /// Returns whether a path has been queued before.
+    fn contains(&self, path: &StorePath) -> bool {
+        self.states.contains_key(path)
+    }
+
+    /// Queues paths along with their references, which may be among the
+    /// queued paths.
+    ///
+    /// Paths that have been queued before are ignored.
+    fn enqueue(&mut self, paths: Vec<(StorePath, Vec<StorePath>, u64)>) {
+        let paths: Vec<_> = paths
+            .into_iter()
+            .filter(|(path, _, _)| !self.contains(path))
+            .collect();
+
+        // Register the whole batch first, so that references between its
+        // paths are known regardless of their order.
+        for (path, _, _) in &paths {
+            self.states.insert(path.clone(), PathState::Pending);
+        }
+
+        for (path, references, nar_size) in paths {
+            let seq = self.num_queued;
+            self.num_queued += 1;
+
+            let mut remaining = 0;
+            let mut failed_reference = None;
+            for reference in references {
+                match self.states.get(&reference) {
+                    None | Some(PathState::Uploaded) => {}
+                    Some(PathState::Pending) => {
+                        remaining += 1;
+                        self.dependents
+                            .entry(reference)
+                            .or_default()
+                            .push(path.clone());
+                    }
+                    Some(PathState::Failed) => failed_reference = Some(reference),
+                }
+            }
+
+            if remaining == 0 && failed_reference.is_none() {
+                self.ready.insert((nar_size, seq), path);
+            } else {
+                self.waiting.insert(
+                    path.clone(),
+                    WaitingPath {
+                        remaining,
+                        nar_size,
+                        seq,
+                    },
+                );
+
+                if let Some(reference) = failed_reference {
+                    self.fail(path, &reference);
+                }
+            }
+        }
+    }
+
+    /// Returns the number of paths that are waiting to be uploaded.
+    fn len(&self) -> usize {
+        self.ready.len() + self.waiting.len()
+    }
+
+    /// Returns the next path to upload.
+    fn next(&mut self) -> Option<StorePath> {
+        self.ready.pop_first().map(|(_, path)| path)
+    }
+
+    /// Queues a path whose upload failed for another attempt.
+    ///
+    /// Its references are already in the cache, so it's ready right away.
+    /// Retries go before the paths that are already ready, so that the
+    /// paths that reference it don't wait longer than necessary.
+    fn retry(&mut self, path: StorePath) {
+        self.ready.insert((0, self.num_queued), path);
+        self.num_queued += 1;
+    }
+
+    fn finish(&mut self, path: StorePath, success: bool) {
+        if !success {
+            self.states.insert(path.clone(), PathState::Failed);
+            for dependent in self.dependents.remove(&path).unwrap_or_default() {
+                self.fail(dependent, &path);
+            }
+            return;
+        }
+
+        self.states.insert(path.clone(), PathState::Uploaded);
+
+        for dependent in self.dependents.remove(&path).unwrap_or_default() {
+            let Some(waiting) = self.waiting.get_mut(&dependent) else {
+                // It failed because of another reference.
+                continue;
+            };
+
+            waiting.remaining -= 1;
+            if waiting.remaining == 0 {
+                let waiting = self.waiting.remove(&dependent).unwrap();
+                self.ready
+                    .insert((waiting.nar_size, waiting.seq), dependent);
+            }
+        }
+    }
+
+    /// Gives up on a waiting path because `reference` wasn't uploaded,
+    /// along with every path that (indirectly) depends on it.
+    fn fail(&mut self, path: StorePath, reference: &StorePath) {
+        let mut doomed = vec![(path, reference.clone())];
+
+        while let Some((path, reference)) = doomed.pop() {
+            if self.waiting.remove(&path).is_none() {
+                continue;
+            }
+
+            tracing::debug!(
+                "Not uploading '{}' because its reference '{}' was not uploaded",
+                path.name(),
+                reference.name()
+            );
+
+            self.states.insert(path.clone(), PathState::Failed);
+
+            for dependent in self.dependents.remove(&path).unwrap_or_default() {
+                doomed.push((dependent, path.clone()));
+            }
+
+            self.dropped.push((path, reference));
+        }
+    }
+
+    /// Returns the paths that were given up on because a reference wasn't
+    /// uploaded since the last call.
+    fn take_dropped(&mut self) -> Vec<(StorePath, StorePath)> {
+        std::mem::take(&mut self.dropped)
+    }
+
+    /// Gives up on all paths that haven't been uploaded yet, returning
+    /// them.
+    fn abandon(&mut self) -> Vec<StorePath> {
+        let ready = std::mem::take(&mut self.ready).into_values();
+        let waiting = std::mem::take(&mut self.waiting).into_keys();
+        let abandoned: Vec<_> = ready.chain(waiting).collect();
+
+        for path in &abandoned {
+            self.states.insert(path.clone(), PathState::Failed);
         }
+        self.dependents.clear();
 
-        Ok(())
+        abandoned
     }
 }
 
 async fn worker(
-    api: &Api,
+    api: Arc<Api>,
     store: Arc<NixStore>,
     mut channel_rx: UnboundedReceiver<Request>,
+    options: UploadOptions,
+    progress: Arc<Progress>,
     metrics: Arc<telemetry::TelemetryReport>,
     narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
-) -> Result<()> {
-    let mut done = HashSet::new();
+) -> Result<UploadReport> {
+    let resolver = Arc::new(Resolver {
+        api: api.clone(),
+        store: store.clone(),
+        scope: options.scope,
+        upstream: options.upstream.clone(),
+        http_client: reqwest::Client::new(),
+        policy: options.policy.clone(),
+        skipped_paths: options.skipped_paths.clone(),
+        narinfo_negative_cache: narinfo_negative_cache.clone(),
+        resolved: Default::default(),
+        lookups: Default::default(),
+    });
 
-    while let Some(req) = channel_rx.recv().await {
-        match req {
-            Request::Shutdown => {
+    let mut scheduler = Scheduler::default();
+    let mut uploaded_bytes: u64 = 0;
+    let mut report = UploadReport::default();
+    // Enqueue requests whose paths are being worked out.
+    let mut resolving = JoinSet::new();
+    // The enqueued paths of each of those requests.
+    let mut resolving_paths: HashMap<tokio::task::Id, Vec<StorePath>> = HashMap::new();
+    let mut uploads = JoinSet::new();
+    let mut shutting_down = false;
+    let mut deadline = None;
+    // The paths being uploaded by each upload task.
+    let mut in_flight = HashMap::new();
+    let mut derivers = HashMap::new();
+    // Paths whose first upload failed and that have been queued again.
+    let mut retried = HashSet::new();
+    // Paths that weren't uploaded because GitHub rate-limited us, directly
+    // or through a reference. They stay pending in the journal, so that
+    // the next run retries them.
+    let mut rate_limited = HashSet::new();
+    let upload_concurrency = options.concurrency.max(1);
+
+    // Paths that won't be uploaded aren't retried by later runs either.
+    let record_failed = |path: &StorePath| {
+        if let Some(journal) = &options.journal {
+            journal.record_failed(&store.get_full_path(path));
+        }
+    };
+
+    loop {
+        while uploads.len() < upload_concurrency {
+            let Some(path) = scheduler.next() else {
                 break;
+            };
+
+            if api.circuit_breaker_tripped() {
+                tracing::trace!("GitHub Actions gave us a 429, so we're done.",);
+                progress.failed.fetch_add(1, Ordering::Relaxed);
+                report
+                    .failed
+                    .push((path.clone(), "rate-limited by GitHub Actions".to_string()));
+                rate_limited.insert(path.clone());
+                scheduler.finish(path, false);
+                continue;
             }
-            Request::Upload(path) => {
-                if api.circuit_breaker_tripped() {
-                    tracing::trace!("GitHub Actions gave us a 429, so we're done.",);
-                    continue;
-                }
 
-                if !done.insert(path.clone()) {
+            // Uploads that are still in flight aren't accounted for, so
+            // the budget can be overshot by up to `upload_concurrency`
+            // paths.
+            if let Some(max_upload_bytes) = options.max_upload_bytes {
+                if uploaded_bytes >= max_upload_bytes {
+                    if report.skipped_over_budget.is_empty() {
+                        tracing::warn!(
+                            "Uploaded {} bytes, which exhausts the upload budget of {} bytes. Not uploading any more paths.",
+                            uploaded_bytes,
+                            max_upload_bytes
+                        );
+                    }
+
+                    metrics.paths_skipped_over_budget.incr();
+                    report.skipped_over_budget.push(path.clone());
+                    record_failed(&path);
+                    scheduler.finish(path, false);
                     continue;
                 }
+            }
+
+            let api = api.clone();
+            let store = store.clone();
+            let metrics = metrics.clone();
+            let narinfo_negative_cache = narinfo_negative_cache.clone();
 
-                if let Err(err) = upload_path(
-                    api,
-                    store.clone(),
+            let deriver = derivers.get(&path).cloned();
+
+            let task_path = path.clone();
+            let task = uploads.spawn(async move {
+                let compression = options.compression.for_path(&path);
+                let res = upload_path(
+                    &api,
+                    store,
                     &path,
-                    metrics.clone(),
-                    narinfo_negative_cache.clone(),
+                    compression,
+                    deriver,
+                    metrics,
+                    narinfo_negative_cache,
                 )
-                .await
-                {
-                    tracing::error!(
-                        "Upload of path '{}' failed: {}",
-                        store.get_full_path(&path).display(),
-                        err
-                    );
+                .await;
+                (path, res)
+            });
+            in_flight.insert(task.id(), task_path);
+        }
+
+        for (path, reference) in scheduler.take_dropped() {
+            progress.failed.fetch_add(1, Ordering::Relaxed);
+            report.failed.push((
+                path.clone(),
+                format!(
+                    "reference '{}' was not uploaded",
+                    store.get_full_path(&reference).display()
+                ),
+            ));
+
+            if rate_limited.contains(&reference) {
+                rate_limited.insert(path);
+            } else {
+                record_failed(&path);
+            }
+        }
+
+        progress.queued.store(scheduler.len(), Ordering::Relaxed);
+        progress.in_flight.store(uploads.len(), Ordering::Relaxed);
+
+        if shutting_down && resolving.is_empty() && uploads.is_empty() {
+            break;
+        }
+
+        tokio::select! {
+            req = channel_rx.recv(), if !shutting_down => {
+                match req {
+                    Some(Request::Enqueue { store_paths, deriver }) => {
+                        let resolver = resolver.clone();
+                        let task_paths = store_paths.clone();
+                        let task = resolving
+                            .spawn(async move { resolver.resolve(store_paths, deriver).await });
+                        resolving_paths.insert(task.id(), task_paths);
+                    }
+                    Some(Request::Shutdown { deadline: d }) => {
+                        // Let the uploads that are already queued finish.
+                        shutting_down = true;
+                        deadline = d;
+                    }
+                    None => {
+                        shutting_down = true;
+                    }
+                }
+            }
+            _ = sleep_until_deadline(deadline) => {
+                tracing::warn!("Reached the upload deadline, abandoning the remaining uploads");
+
+                resolving.abort_all();
+                uploads.abort_all();
+                report.abandoned = scheduler.abandon();
+                while let Some(res) = uploads.join_next_with_id().await {
+                    if let (path, Err(_)) = finish_upload(&store, &mut in_flight, res) {
+                        report.abandoned.push(path);
+                    }
+                }
+
+                // The closures of requests that were still being worked out
+                // are unknown, so only the enqueued paths themselves can be
+                // reported (and journaled, so the next run picks them up).
+                while resolving.join_next().await.is_some() {}
+                let unresolved: Vec<StorePath> = resolving_paths
+                    .drain()
+                    .flat_map(|(_, paths)| paths)
+                    .filter(|path| !scheduler.contains(path))
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .collect();
+                if let Some(journal) = &options.journal {
+                    let full_paths: Vec<_> = unresolved
+                        .iter()
+                        .map(|path| store.get_full_path(path))
+                        .collect();
+                    journal.record_enqueued(full_paths.iter().map(|p| p.as_path()));
+                }
+                report.abandoned.extend(unresolved);
+                break;
+            }
+            Some(res) = resolving.join_next_with_id() => {
+                let id = match &res {
+                    Ok((id, _)) => *id,
+                    Err(err) => err.id(),
+                };
+                resolving_paths.remove(&id);
+
+                let queued = match res {
+                    Ok((_, Ok(queued))) => queued,
+                    Ok((_, Err(err))) => {
+                        tracing::error!("Failed to work out which paths to upload: {}", err);
+                        continue;
+                    }
+                    Err(err) => {
+                        tracing::error!("Enqueueing paths failed: {}", err);
+                        continue;
+                    }
+                };
+
+                let queued: Vec<_> = queued
+                    .into_iter()
+                    .filter(|queued| !scheduler.contains(&queued.path))
+                    .collect();
+
+                if let Some(journal) = &options.journal {
+                    let full_paths: Vec<_> = queued
+                        .iter()
+                        .map(|queued| store.get_full_path(&queued.path))
+                        .collect();
+                    journal.record_enqueued(full_paths.iter().map(|p| p.as_path()));
+                }
+
+                let mut paths = Vec::with_capacity(queued.len());
+                for queued in queued {
+                    if let Some(deriver) = queued.deriver {
+                        derivers.insert(queued.path.clone(), deriver);
+                    }
+                    paths.push((queued.path, queued.references, queued.nar_size));
+                }
+                scheduler.enqueue(paths);
+            }
+            Some(res) = uploads.join_next_with_id() => {
+                match finish_upload(&store, &mut in_flight, res) {
+                    (path, Ok(size)) => {
+                        derivers.remove(&path);
+                        uploaded_bytes += size as u64;
+                        progress.completed.fetch_add(1, Ordering::Relaxed);
+                        if let Some(journal) = &options.journal {
+                            journal.record_done(&store.get_full_path(&path));
+                        }
+                        scheduler.finish(path, true);
+                    }
+                    (path, Err(err)) if retried.contains(&path) => {
+                        derivers.remove(&path);
+                        progress.failed.fetch_add(1, Ordering::Relaxed);
+                        report.failed.push((path.clone(), err.to_string()));
+                        record_failed(&path);
+                        scheduler.finish(path, false);
+                    }
+                    (path, Err(err)) => {
+                        tracing::info!("Retrying the upload of '{}': {}", path.name(), err);
+                        retried.insert(path.clone());
+                        scheduler.retry(path);
+                    }
                 }
             }
         }
     }
 
-    Ok(())
+    if !scheduler.waiting.is_empty() {
+        tracing::warn!(
+            "{} paths were not uploaded because some of their references were never uploaded",
+            scheduler.waiting.len()
+        );
+    }
+
+    Ok(report)
+}
+
+/// Waits until the deadline, if there is one.
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Logs the outcome of an upload task, returning the path and either the
+/// number of bytes uploaded or the error.
+///
+/// The path is looked up in `in_flight`, so that it's known even if the
+/// task panicked or was aborted.
+fn finish_upload(
+    store: &NixStore,
+    in_flight: &mut HashMap<tokio::task::Id, StorePath>,
+    res: std::result::Result<(tokio::task::Id, (StorePath, Result<usize>)), JoinError>,
+) -> (StorePath, Result<usize>) {
+    let id = match &res {
+        Ok((id, _)) => *id,
+        Err(err) => err.id(),
+    };
+    let path = in_flight
+        .remove(&id)
+        .expect("upload task without an in-flight path");
+
+    let res = match res {
+        Ok((_, (_, res))) => res,
+        Err(err) if err.is_cancelled() => Err(Error::Internal("The upload was aborted".to_owned())),
+        Err(err) => Err(Error::Internal(format!("The upload task failed: {err}"))),
+    };
+
+    if let Err(err) = &res {
+        tracing::error!(
+            "Upload of path '{}' failed: {}",
+            store.get_full_path(&path).display(),
+            err
+        );
+    }
+
+    (path, res)
 }
 
 async fn upload_path(
     api: &Api,
     store: Arc<NixStore>,
     path: &StorePath,
+    compression: NarCompression,
+    deriver: Option<PathBuf>,
     metrics: Arc<telemetry::TelemetryReport>,
     narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
-) -> Result<()> {
+) -> Result<usize> {
     let path_info = store.query_path_info(path.clone()).await?;
 
-    // Upload the NAR.
-    let nar_path = format!("{}.nar.zstd", path_info.nar_hash.to_base32());
+    let system = match &deriver {
+        Some(deriver) => derivation::read_system(deriver).await,
+        None => None,
+    };
+
+    // Upload the NAR, unless a NAR with the same hash is already cached
+    // (e.g. from a reproducible rebuild or an identical CA output). NARs
+    // are looked up by key prefix, so the narinfo URL resolves to the
+    // existing entry either way.
+    let nar_path = format!(
+        "{}.{}",
+        path_info.nar_hash.to_base32(),
+        compression.extension()
+    );
 
-    let nar_allocation = api.allocate_file_with_random_suffix(&nar_path).await?;
+    // The size and hash of the compressed NAR, which we only know if we
+    // upload it ourselves. The narinfos of reused NARs go without
+    // `FileHash` and `FileSize`, which Nix treats as optional: it then
+    // can't verify the download before decompressing it, and doesn't know
+    // the download size in advance.
+    let file_info = if api.get_file_url(&[&nar_path]).await?.is_some() {
+        metrics.nars_reused.incr();
 
-    let nar_stream = store.nar_from_path(path.clone());
+        tracing::debug!("Reusing existing '{}'", nar_path);
 
-    let nar_reader = nar_stream.map_err(std::io::Error::other).into_async_read();
+        None
+    } else {
+        let nar_allocation = api.allocate_file_with_random_suffix(&nar_path).await?;
 
-    let nar_compressor = ZstdEncoder::new(nar_reader.compat());
+        let nar_stream = store.nar_from_path(path.clone());
 
-    let compressed_nar_size = api.upload_file(nar_allocation, nar_compressor).await?;
-    metrics.nars_uploaded.incr();
+        let nar_reader = nar_stream.map_err(std::io::Error::other).into_async_read();
 
-    tracing::debug!(
-        "Uploaded '{}' (size {} -> {})",
-        nar_path,
-        path_info.nar_size,
-        compressed_nar_size
-    );
+        let mut nar_compressor = HashingReader::new(compression.encode(nar_reader.compat()));
+
+        let compressed_nar_size = api.upload_file(nar_allocation, &mut nar_compressor).await?;
+        metrics.nars_uploaded.incr();
+        metrics.bytes_uploaded.add(compressed_nar_size);
+
+        tracing::debug!(
+            "Uploaded '{}' (size {} -> {})",
+            nar_path,
+            path_info.nar_size,
+            compressed_nar_size
+        );
+
+        Some((compressed_nar_size, nar_compressor.finalize()))
+    };
 
     // Upload the narinfo.
     let narinfo_path = format!("{}.narinfo", path.to_hash().as_str());
 
     let narinfo_allocation = api.allocate_file_with_random_suffix(&narinfo_path).await?;
 
-    let narinfo =
-        path_info_to_nar_info(store.clone(), &path_info, format!("nar/{nar_path}")).to_string();
+    let narinfo = path_info_to_nar_info(
+        store.clone(),
+        &path_info,
+        compression,
+        format!("nar/{nar_path}"),
+        file_info.clone(),
+        deriver.as_deref(),
+        system,
+    )
+    .to_string();
 
     tracing::debug!("Uploading '{}'", narinfo_path);
 
-    api.upload_file(narinfo_allocation, narinfo.as_bytes())
+    let narinfo_size = api
+        .upload_file(narinfo_allocation, narinfo.as_bytes())
         .await?;
 
     metrics.narinfos_uploaded.incr();
+    metrics.bytes_uploaded.add(narinfo_size);
 
     narinfo_negative_cache
         .write()
@@ -215,17 +1049,27 @@ async fn upload_path(
         store.get_full_path(path).display()
     );
 
-    Ok(())
+    let compressed_nar_size = file_info.map_or(0, |(size, _)| size);
+
+    Ok(compressed_nar_size + narinfo_size)
 }
 
 // FIXME: move to attic.
-fn path_info_to_nar_info(store: Arc<NixStore>, path_info: &ValidPathInfo, url: String) -> NarInfo {
+fn path_info_to_nar_info(
+    store: Arc<NixStore>,
+    path_info: &ValidPathInfo,
+    compression: NarCompression,
+    url: String,
+    file_info: Option<(usize, Hash)>,
+    deriver: Option<&Path>,
+    system: Option<String>,
+) -> NarInfo {
     NarInfo {
         store_path: store.get_full_path(&path_info.path),
         url,
-        compression: Compression::Zstd,
-        file_hash: None,
-        file_size: None,
+        compression: compression.narinfo_compression(),
+        file_size: file_info.as_ref().map(|(size, _)| *size),
+        file_hash: file_info.map(|(_, hash)| hash),
         nar_hash: path_info.nar_hash.clone(),
         nar_size: path_info.nar_size as usize,
         references: path_info
@@ -243,8 +1087,11 @@ fn path_info_to_nar_info(store: Arc<NixStore>, path_info: &ValidPathInfo, url: S
                     .to_owned()
             })
             .collect(),
-        system: None,
-        deriver: None,
+        system,
+        deriver: deriver
+            .and_then(|d| d.file_name())
+            .and_then(|n| n.to_str())
+            .map(|n| n.to_owned()),
         signature: None,
         ca: path_info.ca.clone(),
         provenance: path_info
@@ -253,3 +1100,111 @@ fn path_info_to_nar_info(store: Arc<NixStore>, path_info: &ValidPathInfo, url: S
             .and_then(|v| serde_json::to_string(v).ok()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(name: &str) -> StorePath {
+        StorePath::from_base_name(format!("{}-{name}", "0".repeat(32)).into()).unwrap()
+    }
+
+    fn drain(scheduler: &mut Scheduler) -> Vec<StorePath> {
+        std::iter::from_fn(|| scheduler.next()).collect()
+    }
+
+    #[test]
+    fn references_go_first_regardless_of_batch_order() {
+        let (a, b, c) = (path("a"), path("b"), path("c"));
+        let mut scheduler = Scheduler::default();
+
+        scheduler.enqueue(vec![
+            (a.clone(), vec![b.clone()], 1),
+            (b.clone(), vec![c.clone()], 2),
+            (c.clone(), vec![], 3),
+        ]);
+        assert_eq!(scheduler.len(), 3);
+
+        assert_eq!(drain(&mut scheduler), vec![c.clone()]);
+        scheduler.finish(c, true);
+        assert_eq!(drain(&mut scheduler), vec![b.clone()]);
+        scheduler.finish(b, true);
+        assert_eq!(drain(&mut scheduler), vec![a.clone()]);
+        scheduler.finish(a, true);
+
+        assert_eq!(scheduler.len(), 0);
+        assert!(scheduler.take_dropped().is_empty());
+    }
+
+    #[test]
+    fn failed_reference_fails_dependents_transitively() {
+        let (a, b, c, d) = (path("a"), path("b"), path("c"), path("d"));
+        let mut scheduler = Scheduler::default();
+
+        scheduler.enqueue(vec![
+            (a.clone(), vec![b.clone()], 1),
+            (b.clone(), vec![c.clone()], 1),
+            (c.clone(), vec![], 1),
+            (d.clone(), vec![], 2),
+        ]);
+
+        assert_eq!(drain(&mut scheduler), vec![c.clone(), d.clone()]);
+        scheduler.finish(c.clone(), false);
+        scheduler.finish(d.clone(), true);
+
+        assert_eq!(
+            scheduler.take_dropped(),
+            vec![(b.clone(), c.clone()), (a.clone(), b.clone())]
+        );
+        assert_eq!(scheduler.len(), 0);
+
+        // Paths queued later that reference a failed path are dropped too.
+        let e = path("e");
+        scheduler.enqueue(vec![(e.clone(), vec![a.clone(), d], 1)]);
+        assert_eq!(scheduler.take_dropped(), vec![(e, a)]);
+        assert!(scheduler.next().is_none());
+    }
+
+    #[test]
+    fn retried_path_unblocks_dependents() {
+        let (a, b, c) = (path("a"), path("b"), path("c"));
+        let mut scheduler = Scheduler::default();
+
+        scheduler.enqueue(vec![
+            (a.clone(), vec![b.clone()], 1),
+            (b.clone(), vec![], 1),
+            (c.clone(), vec![], 100),
+        ]);
+
+        // The retry goes before the paths that were already ready.
+        assert_eq!(scheduler.next(), Some(b.clone()));
+        scheduler.retry(b.clone());
+        assert_eq!(scheduler.next(), Some(b.clone()));
+        scheduler.finish(b, true);
+
+        assert_eq!(drain(&mut scheduler), vec![a, c]);
+        assert!(scheduler.take_dropped().is_empty());
+    }
+
+    #[test]
+    fn abandon_returns_ready_and_waiting_paths() {
+        let (a, b, c) = (path("a"), path("b"), path("c"));
+        let mut scheduler = Scheduler::default();
+
+        scheduler.enqueue(vec![
+            (a.clone(), vec![b.clone()], 1),
+            (b.clone(), vec![], 1),
+        ]);
+
+        let abandoned: HashSet<_> = scheduler.abandon().into_iter().collect();
+        assert_eq!(abandoned, HashSet::from([a.clone(), b]));
+        assert_eq!(scheduler.len(), 0);
+        assert!(scheduler.next().is_none());
+        assert!(scheduler.contains(&a));
+
+        // Abandoned paths aren't in the cache, so their dependents can't be
+        // uploaded either.
+        scheduler.enqueue(vec![(c.clone(), vec![a.clone()], 1)]);
+        assert_eq!(scheduler.take_dropped(), vec![(c, a)]);
+    }
+}
@@ -0,0 +1,247 @@
+//! Upload journal.
+//!
+//! The GitHub Actions Cache upload queue only lives in memory, so paths
+//! that are still queued when the process is killed would be lost. The
+//! journal is an append-only log of enqueued and uploaded paths that is
+//! replayed at startup, so that the next run can pick up where the
+//! previous one left off.
+//!
+//! Each line is `enqueue <path>`, `done <path>` or `failed <path>`,
+//! where `<path>` is a full store path. Paths that failed for good (as
+//! opposed to being interrupted) aren't retried, since they would most
+//! likely fail again.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug)]
+pub struct Journal {
+    /// The path of the journal.
+    path: PathBuf,
+
+    /// The journal, opened for appending.
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Opens the journal, creating it if it doesn't exist.
+    ///
+    /// Returns the journal, and the paths whose upload was interrupted, in
+    /// the order they were enqueued. Paths that no longer exist, e.g.
+    /// because they were garbage-collected since, are left out. The journal
+    /// is compacted to just the returned paths.
+    pub fn open(path: &Path) -> Result<(Self, Vec<PathBuf>)> {
+        let mut pending = match File::open(path) {
+            Ok(file) => read_pending(BufReader::new(file))
+                .map_err(|e| Error::Io(e, format!("Reading {}", path.display())))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(Error::Io(e, format!("Opening {}", path.display()))),
+        };
+        pending.retain(|path| path.exists());
+
+        compact(path, &pending)
+            .map_err(|e| Error::Io(e, format!("Compacting {}", path.display())))?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| Error::Io(e, format!("Opening {}", path.display())))?;
+
+        let journal = Self {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+        };
+
+        Ok((journal, pending))
+    }
+
+    /// Records that paths have been enqueued.
+    pub fn record_enqueued<'a>(&self, paths: impl IntoIterator<Item = &'a Path>) {
+        let lines: String = paths
+            .into_iter()
+            .map(|path| format!("enqueue {}\n", path.display()))
+            .collect();
+        self.append(&lines);
+    }
+
+    /// Records that a path has been uploaded.
+    pub fn record_done(&self, path: &Path) {
+        self.append(&format!("done {}\n", path.display()));
+    }
+
+    /// Records that a path won't be uploaded, e.g. because its upload
+    /// failed even after retrying or the upload budget was exhausted.
+    pub fn record_failed(&self, path: &Path) {
+        self.append(&format!("failed {}\n", path.display()));
+    }
+
+    /// Appends to the journal.
+    ///
+    /// Failures are logged rather than returned, since the journal only
+    /// helps later runs and shouldn't stop uploads.
+    fn append(&self, lines: &str) {
+        let mut file = self.file.lock().expect("journal lock poisoned");
+
+        if let Err(e) = file.write_all(lines.as_bytes()) {
+            tracing::warn!("Failed to write to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Returns the paths that were enqueued but neither uploaded nor given up
+/// on.
+fn read_pending(journal: impl BufRead) -> std::io::Result<Vec<PathBuf>> {
+    // Maps pending paths to the line on which they were (first) enqueued.
+    let mut pending = HashMap::new();
+
+    for (i, line) in journal.lines().enumerate() {
+        let line = line?;
+
+        match line.split_once(' ') {
+            Some(("enqueue", path)) => {
+                pending.entry(PathBuf::from(path)).or_insert(i);
+            }
+            Some(("done" | "failed", path)) => {
+                pending.remove(Path::new(path));
+            }
+            _ => {
+                // Most likely a line that was cut off by a crash.
+                tracing::debug!("Ignoring malformed journal line {:?}", line);
+            }
+        }
+    }
+
+    let mut pending: Vec<_> = pending.into_iter().collect();
+    pending.sort_by_key(|(_, i)| *i);
+
+    Ok(pending.into_iter().map(|(path, _)| path).collect())
+}
+
+/// Atomically replaces the journal with one that only enqueues `pending`.
+fn compact(path: &Path, pending: &[PathBuf]) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file = File::create(&tmp_path)?;
+    for pending_path in pending {
+        writeln!(file, "enqueue {}", pending_path.display())?;
+    }
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(journal: &str) -> Vec<PathBuf> {
+        read_pending(journal.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn finished_paths_are_not_pending() {
+        let journal = "\
+enqueue /nix/store/aaa-a
+enqueue /nix/store/bbb-b
+enqueue /nix/store/ccc-c
+done /nix/store/aaa-a
+failed /nix/store/ccc-c
+";
+        assert_eq!(pending(journal), vec![PathBuf::from("/nix/store/bbb-b")]);
+    }
+
+    #[test]
+    fn pending_paths_keep_their_first_position() {
+        let journal = "\
+enqueue /nix/store/bbb-b
+enqueue /nix/store/aaa-a
+enqueue /nix/store/bbb-b
+";
+        assert_eq!(
+            pending(journal),
+            vec![
+                PathBuf::from("/nix/store/bbb-b"),
+                PathBuf::from("/nix/store/aaa-a"),
+            ]
+        );
+    }
+
+    #[test]
+    fn paths_can_be_enqueued_again_after_finishing() {
+        let journal = "\
+enqueue /nix/store/aaa-a
+done /nix/store/aaa-a
+enqueue /nix/store/aaa-a
+";
+        assert_eq!(pending(journal), vec![PathBuf::from("/nix/store/aaa-a")]);
+    }
+
+    #[test]
+    fn malformed_lines_are_ignored() {
+        let journal = "\
+enqueue /nix/store/aaa-a
+bogus
+enqueue /nix/store/bbb-b
+done /nix/sto";
+        assert_eq!(
+            pending(journal),
+            vec![
+                PathBuf::from("/nix/store/aaa-a"),
+                PathBuf::from("/nix/store/bbb-b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn compact_keeps_only_pending_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        std::fs::write(
+            &path,
+            "enqueue /nix/store/aaa-a\nenqueue /nix/store/bbb-b\ndone /nix/store/aaa-a\n",
+        )
+        .unwrap();
+
+        let before = pending(&std::fs::read_to_string(&path).unwrap());
+        compact(&path, &before).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, "enqueue /nix/store/bbb-b\n");
+        assert_eq!(pending(&contents), before);
+        assert!(!dir.path().join("journal.tmp").exists());
+    }
+
+    #[test]
+    fn open_forgets_missing_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let present = dir.path().join("present");
+        let missing = dir.path().join("missing");
+        std::fs::write(&present, "").unwrap();
+        std::fs::write(
+            &path,
+            format!(
+                "enqueue {}\nenqueue {}\n",
+                missing.display(),
+                present.display()
+            ),
+        )
+        .unwrap();
+
+        let (_journal, pending) = Journal::open(&path).unwrap();
+
+        assert_eq!(pending, vec![present.clone()]);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("enqueue {}\n", present.display())
+        );
+    }
+}
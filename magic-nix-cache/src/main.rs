@@ -14,14 +14,23 @@
 
 mod api;
 mod binary_cache;
+mod compression;
+mod derivation;
 mod env;
 mod error;
 mod flakehub;
 mod gha;
 mod github;
+mod journal;
+mod namespace;
 mod narinfo;
+mod netrc;
 mod pbh;
+mod policy;
+mod prune;
+mod sse;
 mod telemetry;
+mod token;
 mod util;
 
 use std::collections::HashSet;
@@ -68,12 +77,44 @@ struct Args {
     #[arg(short = 'l', long, default_value = "127.0.0.1:3000")]
     listen: SocketAddr,
 
-    /// The cache version.
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    namespace: namespace::NamespaceArgs,
+
+    /// The maximum number of compressed bytes to upload to the GitHub Actions Cache.
+    ///
+    /// GitHub evicts the entire repository cache once it exceeds its size
+    /// limit, so this protects the caches of other workflows. Once reached,
+    /// further paths are skipped and reported when the workflow finishes.
+    #[arg(long)]
+    max_upload_bytes: Option<u64>,
+
+    /// The number of store paths to upload to the GitHub Actions Cache at the same time.
+    #[arg(long, default_value_t = 8)]
+    upload_concurrency: usize,
+
+    /// Which store paths to upload.
+    #[arg(long, value_enum, default_value_t = UploadScope::Closure)]
+    upload_scope: UploadScope,
+
+    /// The compression to apply to NARs uploaded to the GitHub Actions Cache.
     ///
-    /// Only caches with the same version string are visible.
-    /// Using another version string allows you to "bust" the cache.
+    /// One of `none`, `xz`, `zstd`, `zstd:<level>`, `br`, or `auto` to skip
+    /// compressing paths that look like they're already compressed.
+    #[arg(long, default_value = "zstd")]
+    compression: compression::NarCompression,
+
+    /// A file in which to keep track of pending uploads to the GitHub Actions Cache.
+    ///
+    /// Paths that were still queued when a previous run was interrupted
+    /// are uploaded at startup.
     #[arg(long)]
-    cache_version: Option<String>,
+    upload_journal: Option<PathBuf>,
+
+    #[command(flatten)]
+    policy: policy::Policy,
 
     /// The upstream cache.
     ///
@@ -101,8 +142,18 @@ struct Args {
     #[arg(long, default_value = "https://cache.flakehub.com")]
     flakehub_cache_server: reqwest::Url,
 
+    /// The flakes whose FlakeHub caches to use.
+    ///
+    /// Can be given several times to push to several caches. Defaults to
+    /// the project that the token belongs to.
     #[arg(long)]
-    flakehub_flake_name: Option<String>,
+    flakehub_flake_name: Vec<String>,
+
+    #[command(flatten)]
+    flakehub_push: flakehub::PushOptions,
+
+    #[command(flatten)]
+    flakehub_token: token::TokenOptions,
 
     /// The location of `nix.conf`.
     #[arg(long, default_value_os_t = default_nix_conf())]
@@ -129,6 +180,12 @@ struct Args {
     diff_store: bool,
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Delete duplicate and stale entries from the GitHub Actions Cache.
+    Prune(prune::PruneArgs),
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
 pub enum CacheTrinary {
     NoPreference,
@@ -146,6 +203,20 @@ impl From<Option<Option<CacheTrinary>>> for CacheTrinary {
     }
 }
 
+/// Which store paths to upload.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum UploadScope {
+    /// The built paths and their entire closure.
+    Closure,
+
+    /// Only the built paths.
+    Outputs,
+
+    /// The built paths, plus the members of their closure that are
+    /// neither in the cache nor in the upstream cache.
+    OutputsPlusMissing,
+}
+
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Dnixd {
     Available,
@@ -361,6 +432,9 @@ async fn main_cli(args: Args, recorder: detsys_ids_client::Recorder) -> Result<(
         )
         .await;
 
+    let policy = Arc::new(args.policy.clone());
+    let skipped_paths = Arc::new(policy::SkippedPaths::new(metrics.clone()));
+
     let flakehub_state = if let Some(auth_method) = flakehub_auth_method {
         let flakehub_cache_server = &args.flakehub_cache_server;
 
@@ -368,12 +442,22 @@ async fn main_cli(args: Args, recorder: detsys_ids_client::Recorder) -> Result<(
 
         let flakehub_flake_name = &args.flakehub_flake_name;
 
+        // The push session always skips paths that FlakeHub already has, so
+        // `outputs-plus-missing` behaves like `closure` here.
+        let mut push_options = args.flakehub_push.clone();
+        push_options.no_closure |= args.upload_scope == UploadScope::Outputs;
+
+        let token_provider = token::provider_for(environment, &args.flakehub_token)?;
+
         match flakehub::init_cache(
-            environment,
+            token_provider,
             flakehub_api_server,
             flakehub_cache_server,
             flakehub_flake_name,
+            push_options,
             store.clone(),
+            policy.clone(),
+            skipped_paths.clone(),
             &auth_method,
         )
         .await
@@ -422,26 +506,89 @@ async fn main_cli(args: Args, recorder: detsys_ids_client::Recorder) -> Result<(
     {
         tracing::info!("Loading credentials from environment");
 
-        let credentials = Credentials::load_from_env()
-            .with_context(|| "Failed to load credentials from environment (see README.md)")?;
+        let credentials = if environment.is_forgejo() {
+            // Forgejo and Gitea only implement the v1 protocol.
+            Credentials::load_from_env_v1()
+        } else {
+            Credentials::load_from_env()
+        }
+        .with_context(|| "Failed to load credentials from environment (see README.md)")?;
+
+        let namespace = args.namespace.determine().await;
+
+        let (journal, interrupted_paths) = match &args.upload_journal {
+            Some(path) => {
+                let (journal, pending) = journal::Journal::open(path)
+                    .with_context(|| "Failed to open the upload journal")?;
+                (Some(Arc::new(journal)), pending)
+            }
+            None => (None, Vec::new()),
+        };
 
         let gha_cache = gha::GhaCache::new(
             credentials,
-            args.cache_version,
+            &namespace,
+            gha::UploadOptions {
+                max_upload_bytes: args.max_upload_bytes,
+                concurrency: args.upload_concurrency,
+                scope: args.upload_scope,
+                upstream: args.upstream.clone(),
+                compression: args.compression,
+                policy: policy.clone(),
+                skipped_paths: skipped_paths.clone(),
+                journal,
+            },
             store.clone(),
             metrics.clone(),
             narinfo_negative_cache.clone(),
         )
         .with_context(|| "Failed to initialize GitHub Actions Cache API")?;
 
+        tracing::info!(
+            "Using cache namespace \"{namespace}\" (version {})",
+            gha_cache.api.version()
+        );
+
         nix_conf
-            .write_all(format!("extra-substituters = http://{}?trusted=1&compression=zstd&parallel-compression=true&priority=1\n", &listener_addr).as_bytes())
+            .write_all(
+                format!(
+                    "extra-substituters = http://{}?trusted=1&{}&priority=1\n",
+                    &listener_addr,
+                    args.compression.nix_store_params()
+                )
+                .as_bytes(),
+            )
             .with_context(|| "Writing to nix.conf")?;
 
+        let interrupted_paths: Vec<_> = interrupted_paths
+            .into_iter()
+            .filter_map(|path| match store.follow_store_path(&path) {
+                Ok(store_path) => Some(store_path),
+                Err(err) => {
+                    tracing::warn!(
+                        "Not resuming the upload of '{}' from the journal: {}",
+                        path.display(),
+                        err
+                    );
+                    None
+                }
+            })
+            .collect();
+
+        if !interrupted_paths.is_empty() {
+            tracing::info!(
+                "Resuming the upload of {} paths from an interrupted run",
+                interrupted_paths.len()
+            );
+            gha_cache
+                .enqueue_paths(interrupted_paths, None)
+                .with_context(|| "Failed to enqueue interrupted uploads")?;
+        }
+
         tracing::info!("Native GitHub Action cache is enabled.");
         Some(gha_cache)
     } else {
-        if environment.is_github_actions() {
+        if environment.is_github_actions() || environment.is_forgejo() {
             tracing::info!("Native GitHub Action cache is disabled.");
         }
 
@@ -574,6 +721,11 @@ async fn main() -> Result<()> {
         Err(_) => {
             let args = Args::parse();
 
+            if let Some(Command::Prune(prune_args)) = args.command {
+                let _guard = init_logging()?;
+                return prune::prune(prune_args, &args.namespace).await;
+            }
+
             let (recorder, client_worker) = detsys_ids_client::builder!()
                 .endpoint(args.diagnostic_endpoint.clone())
                 .build_or_default()
@@ -0,0 +1,159 @@
+//! Cache namespacing.
+//!
+//! Objects in the GitHub Actions Cache are only visible to clients that
+//! use the same cache version. We derive that version from an explicit
+//! set of components, so that upgrading Magic Nix Cache doesn't bust
+//! every existing cache unless the crate version is selected.
+
+use std::fmt::{self, Display};
+
+use tokio::process::Command;
+
+/// Command-line options that select the cache namespace.
+///
+/// These are global, so that subcommands such as `prune` act on the same
+/// namespace no matter where on the command line they are given.
+#[derive(clap::Args, Debug)]
+pub struct NamespaceArgs {
+    /// The cache salt.
+    ///
+    /// Only caches with the same namespace are visible.
+    /// Using another salt allows you to "bust" the cache.
+    #[arg(long, global = true)]
+    cache_salt: Option<String>,
+
+    /// Deprecated: use `--cache-salt` instead.
+    #[arg(long, global = true, hide = true)]
+    cache_version: Option<String>,
+
+    /// The components of the cache namespace.
+    ///
+    /// Selecting `crate-version` makes every upgrade of Magic Nix Cache
+    /// start from an empty cache.
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        global = true,
+        default_values_t = [NamespaceComponent::Salt],
+    )]
+    cache_namespace: Vec<NamespaceComponent>,
+}
+
+impl NamespaceArgs {
+    /// Resolves the selected namespace.
+    pub async fn determine(&self) -> CacheNamespace {
+        if self.cache_version.is_some() {
+            tracing::warn!(
+                "--cache-version is deprecated and is used as the cache salt. Pass --cache-salt instead, and drop it if it's only there to start a fresh cache on upgrades, which --cache-namespace crate-version does."
+            );
+        }
+
+        let salt = self.cache_salt.as_deref().or(self.cache_version.as_deref());
+        CacheNamespace::determine(&self.cache_namespace, salt).await
+    }
+}
+
+/// A component of the cache namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum NamespaceComponent {
+    /// The Nix system type of the host, e.g. `x86_64-linux`.
+    System,
+
+    /// The major and minor version of the installed Nix.
+    NixVersion,
+
+    /// The user-provided salt (`--cache-salt`).
+    Salt,
+
+    /// The version of Magic Nix Cache.
+    CrateVersion,
+}
+
+/// The resolved cache namespace.
+#[derive(Debug, Clone)]
+pub struct CacheNamespace {
+    components: Vec<(NamespaceComponent, String)>,
+}
+
+impl CacheNamespace {
+    /// Resolves the selected components against the current environment.
+    ///
+    /// Components are ordered as selected and deduplicated. The salt
+    /// component is omitted if no salt was provided.
+    pub async fn determine(selected: &[NamespaceComponent], salt: Option<&str>) -> Self {
+        let mut components = Vec::new();
+
+        for component in selected {
+            if components.iter().any(|(c, _)| c == component) {
+                continue;
+            }
+
+            let value = match component {
+                NamespaceComponent::System => nix_system(),
+                NamespaceComponent::NixVersion => nix_version().await.unwrap_or_else(|| {
+                    tracing::warn!("Could not determine the Nix version for the cache namespace");
+                    "unknown".to_owned()
+                }),
+                NamespaceComponent::Salt => match salt {
+                    Some(salt) => salt.to_owned(),
+                    None => continue,
+                },
+                NamespaceComponent::CrateVersion => env!("CARGO_PKG_VERSION").to_owned(),
+            };
+
+            components.push((*component, value));
+        }
+
+        Self { components }
+    }
+}
+
+impl Display for CacheNamespace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (component, value)) in self.components.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+
+            let name = match component {
+                NamespaceComponent::System => "system",
+                NamespaceComponent::NixVersion => "nix-version",
+                NamespaceComponent::Salt => "salt",
+                NamespaceComponent::CrateVersion => "crate-version",
+            };
+
+            write!(f, "{name}={value}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the Nix system type of the host.
+fn nix_system() -> String {
+    let os = match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+
+    format!("{}-{}", std::env::consts::ARCH, os)
+}
+
+/// Returns the `major.minor` version of the Nix in `PATH`.
+///
+/// Patch releases don't affect the compatibility of cached objects,
+/// so they are left out to avoid needless cache busting.
+async fn nix_version() -> Option<String> {
+    let output = Command::new("nix").arg("--version").output().await.ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    // e.g. `nix (Nix) 2.24.9` or `nix (Determinate Nix 3.6.2) 2.29.0`
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout.split_whitespace().last()?;
+
+    Some(version.split('.').take(2).collect::<Vec<_>>().join("."))
+}
@@ -0,0 +1,431 @@
+//! Editing netrc files.
+//!
+//! The netrc files we update belong to the user (or to Nix), so edits only
+//! touch the values that change and leave the rest of the file, including
+//! comments, formatting and entries we don't understand, as it was.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+/// A parsed netrc file.
+#[derive(Debug)]
+pub struct Netrc {
+    /// The original contents.
+    contents: String,
+
+    /// The `machine` and `default` entries.
+    entries: Vec<Entry>,
+
+    /// Replacements of parts of `contents`, in no particular order.
+    edits: Vec<(Range<usize>, String)>,
+
+    /// Entries to append.
+    appended: String,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// The machine name, or `None` for the `default` entry.
+    machine: Option<String>,
+
+    /// The `login`, `password` and `account` fields.
+    fields: Vec<Field>,
+
+    /// The end of the last token of the entry.
+    end: usize,
+}
+
+#[derive(Debug)]
+struct Field {
+    key: String,
+    value: String,
+
+    /// The span of the (possibly quoted) value.
+    span: Range<usize>,
+}
+
+impl Netrc {
+    pub fn parse(contents: String) -> Self {
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut tokens = tokenize(&contents).into_iter();
+
+        while let Some(token) = tokens.next() {
+            match token.text.as_str() {
+                "machine" => {
+                    let Some(name) = tokens.next() else { break };
+                    entries.push(Entry {
+                        machine: Some(name.text),
+                        fields: Vec::new(),
+                        end: name.span.end,
+                    });
+                }
+                "default" => entries.push(Entry {
+                    machine: None,
+                    fields: Vec::new(),
+                    end: token.span.end,
+                }),
+                key @ ("login" | "password" | "account") => {
+                    let Some(value) = tokens.next() else { break };
+                    if let Some(entry) = entries.last_mut() {
+                        entry.end = value.span.end;
+                        entry.fields.push(Field {
+                            key: key.to_owned(),
+                            value: value.text,
+                            span: value.span,
+                        });
+                    }
+                }
+                _ => {
+                    // Macro definitions, or tokens we don't know about.
+                }
+            }
+        }
+
+        Self {
+            contents,
+            entries,
+            edits: Vec::new(),
+            appended: String::new(),
+        }
+    }
+
+    /// Returns whether there is an entry for `machine`.
+    pub fn has_machine(&self, machine: &str) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.machine.as_deref() == Some(machine))
+    }
+
+    /// Returns the login of the entry for `machine`.
+    pub fn login(&self, machine: &str) -> Option<&str> {
+        self.field(machine, "login")
+    }
+
+    /// Returns the password of the entry for `machine`.
+    pub fn password(&self, machine: &str) -> Option<&str> {
+        self.field(machine, "password")
+    }
+
+    fn field(&self, machine: &str, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|entry| entry.machine.as_deref() == Some(machine))?
+            .fields
+            .iter()
+            .find(|field| field.key == key)
+            .map(|field| field.value.as_str())
+    }
+
+    /// Adds an entry for `machine`.
+    pub fn add_machine(&mut self, machine: &str, login: &str, password: &str) {
+        self.appended.push_str(&format!(
+            "\nmachine {} login {} password {}\n",
+            quote(machine),
+            quote(login),
+            quote(password)
+        ));
+    }
+
+    /// Replaces every password that is exactly `old` with `new`, returning
+    /// the number of entries that were updated.
+    pub fn replace_password(&mut self, old: &str, new: &str) -> usize {
+        let spans: Vec<_> = self
+            .entries
+            .iter()
+            .flat_map(|entry| &entry.fields)
+            .filter(|field| field.key == "password" && field.value == old)
+            .map(|field| field.span.clone())
+            .collect();
+
+        let num_updated = spans.len();
+        for span in spans {
+            self.edits.push((span, quote(new)));
+        }
+
+        num_updated
+    }
+
+    /// Returns whether the file has been edited.
+    pub fn is_modified(&self) -> bool {
+        !self.edits.is_empty() || !self.appended.is_empty()
+    }
+
+    /// Returns the edited contents.
+    pub fn serialize(&self) -> String {
+        let mut edits: Vec<_> = self.edits.iter().collect();
+        edits.sort_by_key(|(span, _)| span.start);
+
+        let mut serialized = String::with_capacity(self.contents.len() + self.appended.len());
+        let mut pos = 0;
+        for (span, replacement) in edits {
+            serialized.push_str(&self.contents[pos..span.start]);
+            serialized.push_str(replacement);
+            pos = span.end;
+        }
+        serialized.push_str(&self.contents[pos..]);
+
+        if !self.appended.is_empty() {
+            if !serialized.is_empty() && !serialized.ends_with('\n') {
+                serialized.push('\n');
+            }
+            serialized.push_str(&self.appended);
+        }
+
+        serialized
+    }
+}
+
+/// Edits the netrc file at `path`.
+///
+/// The file is locked against other writers (through `<path>.lock`) while
+/// it's being edited, and replaced atomically with a file that has the
+/// same permissions.
+pub async fn edit<F>(path: &Path, edit: F) -> Result<()>
+where
+    F: FnOnce(&mut Netrc) + Send + 'static,
+{
+    let path = path.to_owned();
+
+    tokio::task::spawn_blocking(move || edit_blocking(&path, edit))
+        .await
+        .map_err(|e| Error::Internal(format!("Editing netrc panicked: {e}")))?
+}
+
+fn edit_blocking<F>(path: &Path, edit: F) -> Result<()>
+where
+    F: FnOnce(&mut Netrc),
+{
+    let lock_path = with_suffix(path, ".lock");
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| Error::Io(e, format!("Opening {}", lock_path.display())))?;
+    lock.lock()
+        .map_err(|e| Error::Io(e, format!("Locking {}", lock_path.display())))?;
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| Error::Io(e, format!("Reading {}", path.display())))?;
+    let permissions = std::fs::metadata(path)
+        .map_err(|e| Error::Io(e, format!("Getting metadata of {}", path.display())))?
+        .permissions();
+
+    let mut netrc = Netrc::parse(contents);
+    edit(&mut netrc);
+
+    if !netrc.is_modified() {
+        return Ok(());
+    }
+
+    // NOTE(cole-h): create the temporary file right next to the real one so we don't run into
+    // cross-device linking issues when renaming
+    let tmp_path = with_suffix(path, ".tmp");
+    let write_tmp = || -> std::io::Result<()> {
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.set_permissions(permissions)?;
+        tmp.write_all(netrc.serialize().as_bytes())?;
+        tmp.sync_all()
+    };
+    write_tmp().map_err(|e| Error::Io(e, format!("Writing {}", tmp_path.display())))?;
+
+    std::fs::rename(&tmp_path, path).map_err(|e| {
+        Error::Io(
+            e,
+            format!("Renaming {} to {}", tmp_path.display(), path.display()),
+        )
+    })?;
+
+    // The lock is released when `lock` is dropped.
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Quotes a value if it can't be written as a bare token.
+fn quote(value: &str) -> String {
+    if !value.is_empty() && !value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        return value.to_owned();
+    }
+
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[derive(Debug)]
+struct Token {
+    text: String,
+    span: Range<usize>,
+}
+
+/// Splits a netrc file into tokens, skipping comments and macro
+/// definitions.
+fn tokenize(s: &str) -> Vec<Token> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut chars = s.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        if c == '#' {
+            while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+            continue;
+        }
+
+        let mut text = String::new();
+        let mut end = start;
+
+        if c == '"' {
+            chars.next();
+            while let Some((i, c)) = chars.next() {
+                end = i + c.len_utf8();
+                match c {
+                    '"' => break,
+                    '\\' => {
+                        if let Some((i, c)) = chars.next() {
+                            end = i + c.len_utf8();
+                            text.push(c);
+                        }
+                    }
+                    c => text.push(c),
+                }
+            }
+        } else {
+            while let Some((i, c)) = chars.next_if(|&(_, c)| !c.is_whitespace()) {
+                end = i + c.len_utf8();
+                text.push(c);
+            }
+        }
+
+        // The body of a macro definition runs from the line after its
+        // name up to the next empty line.
+        let is_macdef_name = tokens.last().is_some_and(|token| token.text == "macdef");
+
+        tokens.push(Token {
+            text,
+            span: start..end,
+        });
+
+        if is_macdef_name {
+            while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+            chars.next();
+            while let Some((i, _)) = chars.peek().copied() {
+                let line_end = s[i..].find('\n').map_or(s.len(), |n| i + n);
+                let is_empty = s[i..line_end].trim().is_empty();
+                while chars.next_if(|&(j, _)| j <= line_end).is_some() {}
+                if is_empty {
+                    break;
+                }
+            }
+        }
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_values_with_escapes() {
+        let contents = r#"machine "my host" login "a \"quoted\" login" password "back\\slash""#;
+        let mut netrc = Netrc::parse(contents.to_owned());
+
+        assert_eq!(netrc.login("my host"), Some(r#"a "quoted" login"#));
+        assert_eq!(netrc.password("my host"), Some(r"back\slash"));
+
+        assert_eq!(netrc.replace_password(r"back\slash", r#"new "pass""#), 1);
+        assert_eq!(
+            netrc.serialize(),
+            r#"machine "my host" login "a \"quoted\" login" password "new \"pass\"""#
+        );
+    }
+
+    #[test]
+    fn skips_macdef_bodies() {
+        let contents = "\
+macdef init
+machine fake login x password y
+cd /pub
+
+machine real login l password p
+";
+        let netrc = Netrc::parse(contents.to_owned());
+
+        assert!(!netrc.has_machine("fake"));
+        assert_eq!(netrc.password("real"), Some("p"));
+    }
+
+    #[test]
+    fn skips_comments() {
+        let contents = "\
+# machine commented login x password y
+machine host login l # password not-this-one
+    password p
+";
+        let netrc = Netrc::parse(contents.to_owned());
+
+        assert!(!netrc.has_machine("commented"));
+        assert_eq!(netrc.login("host"), Some("l"));
+        assert_eq!(netrc.password("host"), Some("p"));
+    }
+
+    #[test]
+    fn replaces_only_matching_passwords() {
+        let contents = "\
+machine jwt.example.com login jwt password jwt-suffix
+machine other login l password jwt
+";
+        let mut netrc = Netrc::parse(contents.to_owned());
+
+        assert_eq!(netrc.replace_password("jwt", "new"), 1);
+        assert_eq!(
+            netrc.serialize(),
+            "\
+machine jwt.example.com login jwt password jwt-suffix
+machine other login l password new
+"
+        );
+    }
+
+    #[test]
+    fn add_machine_without_trailing_newline() {
+        let mut netrc = Netrc::parse("machine a login b password c".to_owned());
+        assert!(!netrc.has_machine("cache"));
+
+        netrc.add_machine("cache", "login", "pass word");
+        assert_eq!(
+            netrc.serialize(),
+            "machine a login b password c\n\nmachine cache login login password \"pass word\"\n"
+        );
+    }
+
+    #[test]
+    fn unmodified_file_round_trips() {
+        let contents =
+            "# comment\nmachine a  login b\tpassword \"c d\"\nmacdef m\nbody\n\ndefault login x";
+        let netrc = Netrc::parse(contents.to_owned());
+
+        assert!(!netrc.is_modified());
+        assert_eq!(netrc.serialize(), contents);
+    }
+}
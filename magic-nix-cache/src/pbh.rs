@@ -16,9 +16,14 @@ use tempfile::NamedTempFile;
 use tokio::net::UnixStream;
 use tokio::process::Command;
 
+use crate::sse;
 use crate::BuiltPathResponseEventV1;
 use crate::State;
 
+/// How long to wait before reconnecting to the built-paths stream, unless
+/// determinate-nixd asks for something else.
+const DEFAULT_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
 pub async fn subscribe_uds_post_build_hook(
     dnixd_uds_socket_path: PathBuf,
     state: State,
@@ -38,7 +43,18 @@ pub async fn subscribe_uds_post_build_hook(
 }
 
 async fn handle_events(state: State, dnixd_uds_socket_path: &PathBuf) -> ! {
+    // The decoder outlives connections, so that we can resume the stream
+    // where it left off.
+    let mut decoder = sse::Decoder::default();
+    let mut connected_before = false;
+
     loop {
+        if connected_before {
+            tokio::time::sleep(decoder.retry().unwrap_or(DEFAULT_RECONNECT_DELAY)).await;
+        }
+        connected_before = true;
+        decoder.reset();
+
         let Ok(socket_conn) = UnixStream::connect(dnixd_uds_socket_path).await else {
             tracing::error!("built-paths: failed to connect to determinate-nixd's socket");
             tokio::time::sleep(std::time::Duration::from_secs(10)).await;
@@ -61,11 +77,15 @@ async fn handle_events(state: State, dnixd_uds_socket_path: &PathBuf) -> ! {
             }
         });
 
-        let request = http::Request::builder()
+        let mut request = http::Request::builder()
             .method(http::Method::GET)
             .uri("http://localhost/events")
-            .body(axum::body::Body::empty());
-        let Ok(request) = request else {
+            .header(http::header::ACCEPT, "text/event-stream");
+        if let Some(last_event_id) = decoder.last_event_id() {
+            tracing::debug!("built-paths: resuming after event {}", last_event_id);
+            request = request.header("Last-Event-ID", last_event_id);
+        }
+        let Ok(request) = request.body(axum::body::Body::empty()) else {
             tracing::error!("built-paths: failed to create request to subscribe");
             continue;
         };
@@ -80,52 +100,55 @@ async fn handle_events(state: State, dnixd_uds_socket_path: &PathBuf) -> ! {
         };
         let mut data = response.into_data_stream();
 
-        while let Some(event_str) = data.next().await {
-            let event_str = match event_str {
-                Ok(event) => event,
+        while let Some(chunk) = data.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
                 Err(e) => {
                     tracing::error!("built-paths: error while receiving: {}", e);
                     break;
                 }
             };
 
-            let Some(event_str) = event_str.strip_prefix("data: ".as_bytes()) else {
-                tracing::debug!("built-paths subscription: ignoring non-data frame");
-                continue;
-            };
-            let Ok(event): core::result::Result<BuiltPathResponseEventV1, _> =
-                serde_json::from_slice(event_str)
-            else {
-                tracing::error!(event_str = %String::from_utf8_lossy(event_str), "failed to decode built-path response as BuiltPathResponseEventV1");
-                continue;
-            };
-
-            let maybe_store_paths = event
-                .outputs
-                .iter()
-                .map(|path| {
-                    state
-                        .store
-                        .follow_store_path(path)
-                        .map_err(|_| anyhow!("failed to collect store paths"))
-                })
-                .collect::<Result<Vec<_>>>();
-
-            let Ok(store_paths) = maybe_store_paths else {
-                tracing::error!("built-paths: encountered an error aggregating build store paths");
-                continue;
-            };
-
-            tracing::debug!("about to enqueue paths: {:?}", store_paths);
-            if let Err(e) = crate::api::enqueue_paths(&state, store_paths).await {
-                tracing::error!(
-                    "built-paths: failed to enqueue paths for drv ({}): {}",
-                    event.drv.display(),
-                    e
-                );
-                continue;
+            for event in decoder.feed(&chunk) {
+                handle_event(&state, &event).await;
             }
         }
+
+        tracing::debug!("built-paths: stream ended, reconnecting");
+    }
+}
+
+async fn handle_event(state: &State, event: &sse::Event) {
+    let Ok(event): core::result::Result<BuiltPathResponseEventV1, _> =
+        serde_json::from_str(&event.data)
+    else {
+        tracing::error!(event_str = %event.data, "failed to decode built-path response as BuiltPathResponseEventV1");
+        return;
+    };
+
+    let maybe_store_paths = event
+        .outputs
+        .iter()
+        .map(|path| {
+            state
+                .store
+                .follow_store_path(path)
+                .map_err(|_| anyhow!("failed to collect store paths"))
+        })
+        .collect::<Result<Vec<_>>>();
+
+    let Ok(store_paths) = maybe_store_paths else {
+        tracing::error!("built-paths: encountered an error aggregating build store paths");
+        return;
+    };
+
+    tracing::debug!("about to enqueue paths: {:?}", store_paths);
+    if let Err(e) = crate::api::enqueue_paths(state, store_paths, Some(event.drv.clone())).await {
+        tracing::error!(
+            "built-paths: failed to enqueue paths for drv ({}): {}",
+            event.drv.display(),
+            e
+        );
     }
 }
 
@@ -214,7 +237,13 @@ pub async fn handle_legacy_post_build_hook(out_paths: &str) -> Result<()> {
         .map(|s| s.trim().to_owned())
         .collect();
 
-    let request = crate::api::EnqueuePathsRequest { store_paths };
+    // Set by Nix for the post-build hook, along with `OUT_PATHS`.
+    let deriver = std::env::var_os("DRV_PATH").map(PathBuf::from);
+
+    let request = crate::api::EnqueuePathsRequest {
+        store_paths,
+        deriver,
+    };
 
     let response = reqwest::Client::new()
         .post(format!("http://{}/api/enqueue-paths", &args.server))
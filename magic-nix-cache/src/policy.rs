@@ -0,0 +1,246 @@
+//! Upload policy.
+//!
+//! The policy decides which store paths may be uploaded at all, e.g. to
+//! keep test results, huge VM images or paths that can't be redistributed
+//! out of the caches.
+
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use attic::nix_store::{NixStore, StorePath, ValidPathInfo};
+use regex::Regex;
+
+use crate::error::Result;
+use crate::telemetry::TelemetryReport;
+
+/// Command-line options that make up the upload policy.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct Policy {
+    /// Only upload store paths whose name matches one of these patterns.
+    ///
+    /// Patterns are globs on the name of the path without the hash (e.g.
+    /// `hello-*`), or regexes if prefixed with `re:`.
+    #[arg(long = "include", value_name = "PATTERN")]
+    include: Vec<Pattern>,
+
+    /// Don't upload store paths whose name matches one of these patterns.
+    ///
+    /// Takes precedence over `--include`.
+    #[arg(long = "exclude", value_name = "PATTERN")]
+    exclude: Vec<Pattern>,
+
+    /// Don't upload store paths whose NAR is larger than this many bytes.
+    #[arg(long)]
+    max_nar_size: Option<u64>,
+
+    /// Only upload the outputs of fixed-output derivations, such as
+    /// fetched sources.
+    #[arg(long)]
+    fixed_output_only: bool,
+}
+
+/// Why a path isn't uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The name is excluded, or not included.
+    Name,
+
+    /// The NAR is larger than `--max-nar-size`.
+    TooLarge,
+
+    /// The path isn't the output of a fixed-output derivation.
+    NotFixedOutput,
+
+    /// The path references a path that isn't uploaded, so it couldn't be
+    /// substituted from the cache.
+    Reference,
+}
+
+impl Policy {
+    /// Returns whether the policy allows every path.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.max_nar_size.is_none()
+            && !self.fixed_output_only
+    }
+
+    /// Checks whether a path may be uploaded.
+    pub fn check(&self, path: &StorePath, path_info: &ValidPathInfo) -> Option<SkipReason> {
+        let name = path.name();
+
+        if self.exclude.iter().any(|p| p.matches(&name))
+            || (!self.include.is_empty() && !self.include.iter().any(|p| p.matches(&name)))
+        {
+            return Some(SkipReason::Name);
+        }
+
+        if self
+            .max_nar_size
+            .is_some_and(|max_nar_size| path_info.nar_size > max_nar_size)
+        {
+            return Some(SkipReason::TooLarge);
+        }
+
+        if self.fixed_output_only
+            && !path_info
+                .ca
+                .as_deref()
+                .is_some_and(|ca| ca.starts_with("fixed:"))
+        {
+            return Some(SkipReason::NotFixedOutput);
+        }
+
+        None
+    }
+
+    /// Returns the members of a closure that may be uploaded.
+    ///
+    /// Members that reference a skipped member are skipped too, so that
+    /// the caches never hold paths whose references can't be substituted.
+    /// References to paths outside of `closure` are assumed to be
+    /// available elsewhere.
+    ///
+    /// Skipped paths are counted in `skipped_paths`.
+    pub fn filter_closure(
+        &self,
+        store: &NixStore,
+        closure: Vec<(StorePath, ValidPathInfo)>,
+        skipped_paths: &SkippedPaths,
+    ) -> Result<Vec<(StorePath, ValidPathInfo)>> {
+        if self.is_empty() {
+            return Ok(closure);
+        }
+
+        let mut skipped = HashSet::new();
+        let mut dependents: HashMap<StorePath, Vec<StorePath>> = HashMap::new();
+
+        for (path, path_info) in &closure {
+            if let Some(reason) = self.check(path, path_info) {
+                tracing::debug!("Not uploading '{}': {:?}", path.name(), reason);
+                skipped_paths.record(path, reason);
+                skipped.insert(path.clone());
+            }
+
+            for reference in &path_info.references {
+                let reference = store.parse_store_path(reference)?;
+                if reference != *path {
+                    dependents.entry(reference).or_default().push(path.clone());
+                }
+            }
+        }
+
+        let mut doomed: Vec<StorePath> = skipped.iter().cloned().collect();
+        while let Some(path) = doomed.pop() {
+            for dependent in dependents.remove(&path).unwrap_or_default() {
+                if skipped.insert(dependent.clone()) {
+                    tracing::debug!(
+                        "Not uploading '{}' because its reference '{}' is not uploaded",
+                        dependent.name(),
+                        path.name()
+                    );
+                    skipped_paths.record(&dependent, SkipReason::Reference);
+                    doomed.push(dependent);
+                }
+            }
+        }
+
+        Ok(closure
+            .into_iter()
+            .filter(|(path, _)| !skipped.contains(path))
+            .collect())
+    }
+}
+
+/// Counts the paths that the policy skips.
+///
+/// Closures that share members are filtered separately (and for each
+/// cache), so this remembers which paths were counted to count each of
+/// them only once.
+#[derive(Debug)]
+pub struct SkippedPaths {
+    metrics: Arc<TelemetryReport>,
+    counted: Mutex<HashSet<StorePath>>,
+}
+
+impl SkippedPaths {
+    pub fn new(metrics: Arc<TelemetryReport>) -> Self {
+        Self {
+            metrics,
+            counted: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn record(&self, path: &StorePath, reason: SkipReason) {
+        let newly_counted = self
+            .counted
+            .lock()
+            .expect("skipped paths lock poisoned")
+            .insert(path.clone());
+        if !newly_counted {
+            return;
+        }
+
+        match reason {
+            SkipReason::Name => self.metrics.paths_skipped_by_name.incr(),
+            SkipReason::TooLarge => self.metrics.paths_skipped_too_large.incr(),
+            SkipReason::NotFixedOutput => self.metrics.paths_skipped_not_fixed_output.incr(),
+            SkipReason::Reference => self.metrics.paths_skipped_reference.incr(),
+        }
+    }
+}
+
+/// A pattern for store path names.
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn matches(&self, name: &str) -> bool {
+        self.0.is_match(name)
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = regex::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(re) = s.strip_prefix("re:") {
+            return Regex::new(re).map(Self);
+        }
+
+        let mut re = String::from("^");
+        for c in s.chars() {
+            match c {
+                '*' => re.push_str(".*"),
+                '?' => re.push('.'),
+                c => re.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        re.push('$');
+
+        Regex::new(&re).map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skipped_paths_are_counted_once() {
+        let metrics = Arc::new(TelemetryReport::default());
+        let skipped_paths = SkippedPaths::new(metrics.clone());
+        let path = |name: &str| {
+            StorePath::from_base_name(format!("{}-{name}", "0".repeat(32)).into()).unwrap()
+        };
+
+        skipped_paths.record(&path("a"), SkipReason::Name);
+        skipped_paths.record(&path("a"), SkipReason::Name);
+        skipped_paths.record(&path("b"), SkipReason::Reference);
+        skipped_paths.record(&path("b"), SkipReason::Reference);
+
+        assert_eq!(metrics.paths_skipped_by_name.get(), 1);
+        assert_eq!(metrics.paths_skipped_reference.get(), 1);
+    }
+}
@@ -0,0 +1,263 @@
+//! Pruning of the GitHub Actions Cache.
+//!
+//! Every upload allocates a fresh key with a random suffix, so
+//! re-uploaded narinfos and NARs accumulate in the cache until GitHub
+//! evicts them. This uses the REST API to delete the entries of our
+//! namespace that are shadowed by a newer copy or have gone stale.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use gha_cache::rest::{CacheEntry, RestApi};
+
+use crate::namespace::NamespaceArgs;
+
+#[derive(clap::Args, Debug)]
+pub struct PruneArgs {
+    /// The GitHub REST API server.
+    #[arg(long, env = "GITHUB_API_URL", default_value = "https://api.github.com")]
+    github_api_url: String,
+
+    /// The repository whose caches to prune, as `owner/name`.
+    #[arg(long, env = "GITHUB_REPOSITORY")]
+    repository: String,
+
+    /// A GitHub token with the `actions: write` permission.
+    #[arg(long, env = "GITHUB_TOKEN", hide_env_values = true)]
+    github_token: String,
+
+    /// Also delete entries that haven't been accessed in this many days.
+    #[arg(long)]
+    older_than_days: Option<u64>,
+
+    /// Only log what would be deleted.
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+}
+
+/// Why an entry is deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reason {
+    /// A newer entry with the same key exists in the same scope.
+    Duplicate,
+
+    /// The entry hasn't been accessed within `--older-than-days`.
+    Stale,
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Duplicate => write!(f, "duplicate"),
+            Self::Stale => write!(f, "stale"),
+        }
+    }
+}
+
+pub async fn prune(args: PruneArgs, namespace: &NamespaceArgs) -> Result<()> {
+    let namespace = namespace.determine().await;
+    let version = gha_cache::api::mutated_version(namespace.to_string().as_bytes());
+
+    tracing::info!("Pruning cache namespace \"{namespace}\" (version {version})");
+
+    let api = RestApi::new(&args.github_api_url, &args.repository, &args.github_token)
+        .with_context(|| "Failed to initialize the GitHub REST API client")?;
+
+    let entries = api
+        .list_caches()
+        .await
+        .with_context(|| format!("Listing the caches of {}", args.repository))?
+        .into_iter()
+        .filter(|entry| entry.version == version)
+        .collect::<Vec<_>>();
+
+    tracing::info!("Found {} entries in the namespace", entries.len());
+
+    let cutoff = args
+        .older_than_days
+        .map(|days| SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60));
+
+    let mut num_deleted = 0;
+    let mut bytes_deleted = 0;
+
+    for (entry, reason) in select_for_deletion(entries, cutoff) {
+        if args.dry_run {
+            tracing::info!(
+                key = entry.key,
+                r#ref = entry.git_ref,
+                "Would delete {reason} entry"
+            );
+        } else {
+            tracing::info!(
+                key = entry.key,
+                r#ref = entry.git_ref,
+                "Deleting {reason} entry"
+            );
+
+            if let Err(err) = api.delete_cache(entry.id).await {
+                tracing::error!("Failed to delete '{}': {}", entry.key, err);
+                continue;
+            }
+        }
+
+        num_deleted += 1;
+        bytes_deleted += entry.size_in_bytes;
+    }
+
+    tracing::info!(
+        "{} {} entries ({} bytes)",
+        if args.dry_run {
+            "Would delete"
+        } else {
+            "Deleted"
+        },
+        num_deleted,
+        bytes_deleted
+    );
+
+    Ok(())
+}
+
+/// Selects the entries to delete.
+///
+/// Within each scope, only the most recently accessed entry for a key
+/// is kept. Kept entries are still deleted if they were last accessed
+/// before `cutoff`.
+fn select_for_deletion(
+    entries: Vec<CacheEntry>,
+    cutoff: Option<SystemTime>,
+) -> Vec<(CacheEntry, Reason)> {
+    let mut groups: HashMap<(String, String), Vec<CacheEntry>> = HashMap::new();
+
+    for entry in entries {
+        groups
+            .entry((entry.git_ref.clone(), base_key(&entry.key).to_owned()))
+            .or_default()
+            .push(entry);
+    }
+
+    let mut doomed = Vec::new();
+
+    for mut group in groups.into_values() {
+        // GitHub returns timestamps in a uniform format, so they sort lexicographically.
+        group.sort_by(|a, b| b.last_accessed_at.cmp(&a.last_accessed_at));
+
+        let mut group = group.into_iter();
+
+        if let Some(newest) = group.next() {
+            if cutoff.is_some_and(|cutoff| is_older_than(&newest, cutoff)) {
+                doomed.push((newest, Reason::Stale));
+            }
+        }
+
+        doomed.extend(group.map(|entry| (entry, Reason::Duplicate)));
+    }
+
+    doomed
+}
+
+/// Strips the random suffix added by `Api::allocate_file_with_random_suffix`.
+fn base_key(key: &str) -> &str {
+    match key.rsplit_once('-') {
+        Some((base, nonce))
+            if nonce.len() == 4 && nonce.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            base
+        }
+        _ => key,
+    }
+}
+
+fn is_older_than(entry: &CacheEntry, cutoff: SystemTime) -> bool {
+    match humantime::parse_rfc3339_weak(&entry.last_accessed_at) {
+        Ok(last_accessed) => last_accessed < cutoff,
+        Err(err) => {
+            tracing::warn!(
+                "Cannot parse the last access time of '{}': {}",
+                entry.key,
+                err
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: i64, key: &str, last_accessed_at: &str) -> CacheEntry {
+        CacheEntry {
+            id,
+            git_ref: "refs/heads/main".to_owned(),
+            key: key.to_owned(),
+            version: "abc".to_owned(),
+            last_accessed_at: last_accessed_at.to_owned(),
+            created_at: "2024-01-01T00:00:00.000Z".to_owned(),
+            size_in_bytes: 1024,
+        }
+    }
+
+    fn selected_ids(selected: &[(CacheEntry, Reason)], reason: Reason) -> Vec<i64> {
+        let mut ids: Vec<_> = selected
+            .iter()
+            .filter(|(_, r)| *r == reason)
+            .map(|(entry, _)| entry.id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn base_key_strips_random_suffix() {
+        assert_eq!(base_key("abc.narinfo-x1Y9"), "abc.narinfo");
+        assert_eq!(base_key("abc.nar.zstd-0000"), "abc.nar.zstd");
+    }
+
+    #[test]
+    fn base_key_keeps_other_keys() {
+        assert_eq!(base_key("abc.narinfo"), "abc.narinfo");
+        assert_eq!(base_key("hello-2.12"), "hello-2.12");
+        assert_eq!(base_key("abc.narinfo-12345"), "abc.narinfo-12345");
+        assert_eq!(base_key("abc.narinfo-a_b!"), "abc.narinfo-a_b!");
+    }
+
+    #[test]
+    fn keeps_newest_duplicate_per_ref() {
+        let entries = vec![
+            entry(1, "abc.narinfo-aaaa", "2024-01-01T00:00:00.000Z"),
+            entry(2, "abc.narinfo-bbbb", "2024-01-03T00:00:00.000Z"),
+            entry(3, "abc.narinfo-cccc", "2024-01-02T00:00:00.000Z"),
+            // The same key in another scope isn't a duplicate.
+            CacheEntry {
+                git_ref: "refs/pull/1/merge".to_owned(),
+                ..entry(4, "abc.narinfo-dddd", "2024-01-01T00:00:00.000Z")
+            },
+            entry(5, "def.narinfo-eeee", "2024-01-01T00:00:00.000Z"),
+        ];
+
+        let selected = select_for_deletion(entries, None);
+
+        assert_eq!(selected_ids(&selected, Reason::Duplicate), vec![1, 3]);
+        assert_eq!(selected_ids(&selected, Reason::Stale), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn deletes_stale_entries_before_cutoff() {
+        let entries = vec![
+            entry(1, "abc.narinfo-aaaa", "2024-01-01T00:00:00.000Z"),
+            entry(2, "abc.narinfo-bbbb", "2024-01-02T00:00:00.000Z"),
+            entry(3, "def.narinfo-cccc", "2024-02-01T00:00:00.000Z"),
+            // Entries whose access time can't be parsed are kept.
+            entry(4, "ghi.narinfo-dddd", "yesterday"),
+        ];
+
+        let cutoff = humantime::parse_rfc3339("2024-01-15T00:00:00Z").unwrap();
+        let selected = select_for_deletion(entries, Some(cutoff));
+
+        assert_eq!(selected_ids(&selected, Reason::Duplicate), vec![1]);
+        assert_eq!(selected_ids(&selected, Reason::Stale), vec![2]);
+    }
+}
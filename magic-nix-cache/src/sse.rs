@@ -0,0 +1,296 @@
+//! Incremental decoding of Server-Sent Events.
+//!
+//! Implements the parsing rules of the [event stream format], independent
+//! of how the stream is split into chunks on the wire: an event may span
+//! several chunks, and a chunk may hold several events.
+//!
+//! [event stream format]: https://html.spec.whatwg.org/multipage/server-sent-events.html#event-stream-interpretation
+
+use std::time::Duration;
+
+/// A dispatched event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// The event type, `message` unless the server set one.
+    pub event: String,
+
+    /// The data, with the lines of multi-line data joined by `\n`.
+    pub data: String,
+
+    /// The last event ID as of this event.
+    pub id: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Decoder {
+    /// The start of a line that hasn't been terminated yet.
+    line: Vec<u8>,
+
+    /// Whether the last chunk ended in `\r`, so that a `\n` at the start of
+    /// the next one belongs to the same line ending.
+    pending_cr: bool,
+
+    /// Whether the start of the stream (and a possible byte order mark)
+    /// has been seen.
+    started: bool,
+
+    /// The fields of the event that is being decoded.
+    event: String,
+    data: String,
+
+    last_event_id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Decoder {
+    /// Decodes a chunk of the stream, returning the events it completes.
+    pub fn feed(&mut self, mut chunk: &[u8]) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        if !self.started && !chunk.is_empty() {
+            let bom = b"\xEF\xBB\xBF";
+            let prefix_len = chunk.len().min(bom.len() - self.line.len());
+            if bom.starts_with(&self.line)
+                && bom[self.line.len()..].starts_with(&chunk[..prefix_len])
+            {
+                // Hold on to what may be the start of a byte order mark
+                // until we know for sure.
+                self.line.extend_from_slice(&chunk[..prefix_len]);
+                chunk = &chunk[prefix_len..];
+                if self.line.len() < bom.len() {
+                    return events;
+                }
+                self.line.clear();
+            }
+            self.started = true;
+        }
+
+        if self.pending_cr {
+            self.pending_cr = false;
+            chunk = chunk.strip_prefix(b"\n").unwrap_or(chunk);
+        }
+
+        while let Some(pos) = chunk.iter().position(|&b| b == b'\n' || b == b'\r') {
+            self.line.extend_from_slice(&chunk[..pos]);
+            let line = std::mem::take(&mut self.line);
+            self.process_line(&String::from_utf8_lossy(&line), &mut events);
+
+            let is_cr = chunk[pos] == b'\r';
+            chunk = &chunk[pos + 1..];
+
+            if is_cr {
+                match chunk.first() {
+                    Some(b'\n') => chunk = &chunk[1..],
+                    Some(_) => {}
+                    None => self.pending_cr = true,
+                }
+            }
+        }
+
+        self.line.extend_from_slice(chunk);
+
+        events
+    }
+
+    /// Returns the ID of the last event, to resume the stream from.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    /// Returns how long the server asked clients to wait before
+    /// reconnecting, if it did.
+    pub fn retry(&self) -> Option<Duration> {
+        self.retry
+    }
+
+    /// Starts decoding a new connection to the same stream.
+    ///
+    /// The last event ID and reconnection time carry over, but a partial
+    /// event is discarded.
+    pub fn reset(&mut self) {
+        *self = Self {
+            last_event_id: self.last_event_id.take(),
+            retry: self.retry,
+            ..Self::default()
+        };
+    }
+
+    fn process_line(&mut self, line: &str, events: &mut Vec<Event>) {
+        if line.is_empty() {
+            self.dispatch(events);
+            return;
+        }
+
+        if line.starts_with(':') {
+            // A comment, e.g. a keep-alive.
+            return;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+
+        match field {
+            "event" => self.event = value.to_owned(),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => self.last_event_id = Some(value.to_owned()),
+            "retry" => {
+                if let Ok(millis) = value.parse::<u64>() {
+                    self.retry = Some(Duration::from_millis(millis));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn dispatch(&mut self, events: &mut Vec<Event>) {
+        let event = std::mem::take(&mut self.event);
+        let mut data = std::mem::take(&mut self.data);
+
+        if data.is_empty() {
+            return;
+        }
+        data.pop();
+
+        events.push(Event {
+            event: if event.is_empty() {
+                "message".to_owned()
+            } else {
+                event
+            },
+            data,
+            id: self.last_event_id.clone(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(data: &str) -> Event {
+        Event {
+            event: "message".to_owned(),
+            data: data.to_owned(),
+            id: None,
+        }
+    }
+
+    fn feed_all<'a>(
+        decoder: &mut Decoder,
+        chunks: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Vec<Event> {
+        chunks
+            .into_iter()
+            .flat_map(|chunk| decoder.feed(chunk))
+            .collect()
+    }
+
+    #[test]
+    fn single_event() {
+        let mut decoder = Decoder::default();
+        assert_eq!(
+            decoder.feed(b"data: {\"a\":1}\n\n"),
+            vec![message("{\"a\":1}")]
+        );
+    }
+
+    #[test]
+    fn several_events_in_one_chunk() {
+        let mut decoder = Decoder::default();
+        assert_eq!(
+            decoder.feed(b"data: one\n\ndata: two\n\ndata: thr"),
+            vec![message("one"), message("two")]
+        );
+        assert_eq!(decoder.feed(b"ee\n\n"), vec![message("three")]);
+    }
+
+    #[test]
+    fn event_split_byte_by_byte() {
+        let stream = b"event: built\nid: 7\ndata: {\"drv\": \"x\"}\n\ndata: next\n\n";
+
+        for line_ending in ["\n", "\r\n", "\r"] {
+            let stream = String::from_utf8_lossy(stream).replace('\n', line_ending);
+            let mut decoder = Decoder::default();
+            let events = feed_all(&mut decoder, stream.as_bytes().chunks(1));
+
+            assert_eq!(
+                events,
+                vec![
+                    Event {
+                        event: "built".to_owned(),
+                        data: "{\"drv\": \"x\"}".to_owned(),
+                        id: Some("7".to_owned()),
+                    },
+                    Event {
+                        event: "message".to_owned(),
+                        data: "next".to_owned(),
+                        id: Some("7".to_owned()),
+                    },
+                ],
+                "line ending {line_ending:?}"
+            );
+            assert_eq!(decoder.last_event_id(), Some("7"));
+        }
+    }
+
+    #[test]
+    fn crlf_split_across_chunks() {
+        let mut decoder = Decoder::default();
+        let events = feed_all(
+            &mut decoder,
+            [&b"data: a\r"[..], b"\ndata: b\r", b"\n\r", b"\n"],
+        );
+        assert_eq!(events, vec![message("a\nb")]);
+    }
+
+    #[test]
+    fn comments_and_unknown_fields_are_ignored() {
+        let mut decoder = Decoder::default();
+        let events = decoder.feed(b": keep-alive\n\nfoo: bar\ndata:x\n: in between\ndata\n\n");
+        assert_eq!(events, vec![message("x\n")]);
+    }
+
+    #[test]
+    fn events_without_data_are_not_dispatched() {
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.feed(b"event: ping\n\nid: 3\n\n"), vec![]);
+        assert_eq!(decoder.last_event_id(), Some("3"));
+        assert_eq!(decoder.feed(b"data: y\n\n")[0].event, "message");
+    }
+
+    #[test]
+    fn byte_order_mark_is_skipped() {
+        let mut decoder = Decoder::default();
+        let events = feed_all(&mut decoder, [&b"\xEF\xBB"[..], b"\xBFdata: z\n\n"]);
+        assert_eq!(events, vec![message("z")]);
+    }
+
+    #[test]
+    fn retry_is_recorded() {
+        let mut decoder = Decoder::default();
+        decoder.feed(b"retry: 2500\nretry: soon\n\n");
+        assert_eq!(decoder.retry(), Some(Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn reset_discards_partial_events() {
+        let mut decoder = Decoder::default();
+        decoder.feed(b"id: 9\ndata: done\n\ndata: partial");
+        decoder.reset();
+
+        assert_eq!(decoder.last_event_id(), Some("9"));
+        assert_eq!(decoder.feed(b"\n\n"), vec![]);
+        assert_eq!(
+            decoder.feed(b"data: fresh\n\n"),
+            vec![Event {
+                id: Some("9".to_owned()),
+                ..message("fresh")
+            }]
+        );
+    }
+}
@@ -17,6 +17,17 @@ pub struct TelemetryReport {
     pub nars_served: Metric,
     pub nars_sent_upstream: Metric,
     pub nars_uploaded: Metric,
+    pub nars_reused: Metric,
+
+    pub bytes_uploaded: Metric,
+    pub paths_skipped_over_budget: Metric,
+    pub paths_skipped_by_name: Metric,
+    pub paths_skipped_too_large: Metric,
+    pub paths_skipped_not_fixed_output: Metric,
+    pub paths_skipped_reference: Metric,
+
+    pub flakehub_paths_pushed: Metric,
+    pub flakehub_paths_failed: Metric,
 
     pub num_original_paths: Metric,
     pub num_final_paths: Metric,
@@ -33,9 +44,17 @@ impl Metric {
         self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     }
 
+    pub fn add(&self, val: usize) {
+        self.0.fetch_add(val, std::sync::atomic::Ordering::Relaxed);
+    }
+
     pub fn set(&self, val: usize) {
         self.0.store(val, std::sync::atomic::Ordering::Relaxed);
     }
+
+    pub fn get(&self) -> usize {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
 }
 
 macro_rules! fact {
@@ -79,6 +98,15 @@ impl TelemetryReport {
             nars_served,
             nars_sent_upstream,
             nars_uploaded,
+            nars_reused,
+            bytes_uploaded,
+            paths_skipped_over_budget,
+            paths_skipped_by_name,
+            paths_skipped_too_large,
+            paths_skipped_not_fixed_output,
+            paths_skipped_reference,
+            flakehub_paths_pushed,
+            flakehub_paths_failed,
             num_original_paths,
             num_final_paths,
             num_new_paths,
@@ -99,6 +127,15 @@ impl TelemetryReport {
         fact!(recorder, nars_served);
         fact!(recorder, nars_sent_upstream);
         fact!(recorder, nars_uploaded);
+        fact!(recorder, nars_reused);
+        fact!(recorder, bytes_uploaded);
+        fact!(recorder, paths_skipped_over_budget);
+        fact!(recorder, paths_skipped_by_name);
+        fact!(recorder, paths_skipped_too_large);
+        fact!(recorder, paths_skipped_not_fixed_output);
+        fact!(recorder, paths_skipped_reference);
+        fact!(recorder, flakehub_paths_pushed);
+        fact!(recorder, flakehub_paths_failed);
         fact!(recorder, num_original_paths);
         fact!(recorder, num_final_paths);
         fact!(recorder, num_new_paths);
@@ -0,0 +1,286 @@
+//! Sources of fresh FlakeHub tokens.
+//!
+//! FlakeHub accepts OIDC tokens from CI systems, which tend to expire
+//! quickly. When authenticating through a netrc file, we periodically get
+//! a new token from one of these sources.
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use reqwest::header::HeaderValue;
+
+use crate::env::Environment;
+use crate::error::{Error, Result};
+
+/// The audience of the tokens that FlakeHub accepts.
+const FLAKEHUB_AUDIENCE: &str = "api.flakehub.com";
+
+/// The GitLab `id_tokens` variable that holds a FlakeHub token, either
+/// directly or as a file variable.
+const GITLAB_ID_TOKEN_VARIABLE: &str = "FLAKEHUB_ID_TOKEN";
+
+/// Command-line options for getting fresh FlakeHub tokens.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct TokenOptions {
+    /// A command that prints a fresh FlakeHub token.
+    ///
+    /// Run with `sh -c` whenever the token needs refreshing. Takes
+    /// precedence over the token source of the CI system.
+    #[arg(long = "flakehub-token-command", value_name = "COMMAND")]
+    command: Option<String>,
+
+    /// A file that contains a FlakeHub token, such as a GitLab `id_tokens`
+    /// file variable.
+    ///
+    /// Re-read whenever the token needs refreshing.
+    #[arg(long = "flakehub-token-file", value_name = "PATH")]
+    file: Option<PathBuf>,
+}
+
+/// A source of FlakeHub tokens.
+pub trait TokenProvider: Send + Sync {
+    /// Describes the source in logs.
+    fn name(&self) -> &'static str;
+
+    /// Gets a fresh token.
+    fn fetch_token(&self) -> BoxFuture<'_, Result<String>>;
+}
+
+/// Returns the token provider to use, if there is one.
+pub fn provider_for(
+    environment: Environment,
+    options: &TokenOptions,
+) -> Result<Option<Box<dyn TokenProvider>>> {
+    if let Some(command) = &options.command {
+        return Ok(Some(Box::new(Command::shell(command))));
+    }
+
+    if let Some(file) = &options.file {
+        return Ok(Some(Box::new(TokenFile { path: file.clone() })));
+    }
+
+    let provider: Box<dyn TokenProvider> = match environment {
+        Environment::GitHubActions => Box::new(GitHubActions::new()?),
+        Environment::Buildkite => Box::new(Command::buildkite()),
+        Environment::GitLabCI => {
+            if std::env::var_os(GITLAB_ID_TOKEN_VARIABLE).is_none() {
+                tracing::warn!(
+                    "Declare a {GITLAB_ID_TOKEN_VARIABLE} ID token with the audience {FLAKEHUB_AUDIENCE} in the job's id_tokens, or pass --flakehub-token-file, so that the FlakeHub token can be refreshed"
+                );
+                return Ok(None);
+            }
+
+            Box::new(GitLabIdToken)
+        }
+        Environment::Forgejo | Environment::Other => return Ok(None),
+    };
+
+    Ok(Some(provider))
+}
+
+/// Requests tokens from the GitHub Actions OIDC provider.
+struct GitHubActions {
+    client: reqwest::Client,
+}
+
+impl GitHubActions {
+    fn new() -> Result<Self> {
+        // NOTE(cole-h): https://docs.github.com/en/actions/deployment/security-hardening-your-deployments/configuring-openid-connect-in-cloud-providers#requesting-the-jwt-using-environment-variables
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::ACCEPT,
+            HeaderValue::from_static("application/json;api-version=2.0"),
+        );
+        headers.insert(
+            reqwest::header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+
+        let client = reqwest::Client::builder()
+            .user_agent(crate::flakehub::USER_AGENT)
+            .default_headers(headers)
+            .build()?;
+
+        Ok(Self { client })
+    }
+
+    async fn request_token(&self) -> Result<String> {
+        let runtime_token = std::env::var("ACTIONS_ID_TOKEN_REQUEST_TOKEN").map_err(|e| {
+            Error::Internal(format!(
+                "ACTIONS_ID_TOKEN_REQUEST_TOKEN was invalid unicode: {e}"
+            ))
+        })?;
+        let runtime_url = std::env::var("ACTIONS_ID_TOKEN_REQUEST_URL").map_err(|e| {
+            Error::Internal(format!(
+                "ACTIONS_ID_TOKEN_REQUEST_URL was invalid unicode: {e}"
+            ))
+        })?;
+
+        let token_request_url = format!("{runtime_url}&audience={FLAKEHUB_AUDIENCE}");
+        let token_response = self
+            .client
+            .request(reqwest::Method::GET, &token_request_url)
+            .bearer_auth(runtime_token)
+            .send()
+            .await
+            .with_context(|| format!("sending request to {token_request_url}"))?;
+
+        if let Err(e) = token_response.error_for_status_ref() {
+            tracing::error!(?e, "Got error response when requesting token");
+            return Err(e)?;
+        }
+
+        #[derive(serde::Deserialize)]
+        struct TokenResponse {
+            value: String,
+        }
+
+        let token_response: TokenResponse = token_response
+            .json()
+            .await
+            .with_context(|| "converting response into json")?;
+
+        Ok(token_response.value)
+    }
+}
+
+impl TokenProvider for GitHubActions {
+    fn name(&self) -> &'static str {
+        "GitHub Actions"
+    }
+
+    fn fetch_token(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(self.request_token())
+    }
+}
+
+/// Reads tokens from the GitLab `id_tokens` variable
+/// [`GITLAB_ID_TOKEN_VARIABLE`].
+struct GitLabIdToken;
+
+impl GitLabIdToken {
+    async fn read_token(&self) -> Result<String> {
+        let value = std::env::var(GITLAB_ID_TOKEN_VARIABLE).map_err(|e| {
+            Error::Internal(format!("{GITLAB_ID_TOKEN_VARIABLE} was not usable: {e}"))
+        })?;
+
+        // File variables hold the path of a file with the token.
+        let path = PathBuf::from(&value);
+        if path.is_absolute() && path.is_file() {
+            return TokenFile { path }.read_token().await;
+        }
+
+        non_empty_token(value, || GITLAB_ID_TOKEN_VARIABLE.to_owned())
+    }
+}
+
+impl TokenProvider for GitLabIdToken {
+    fn name(&self) -> &'static str {
+        "GitLab ID token"
+    }
+
+    fn fetch_token(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(self.read_token())
+    }
+}
+
+/// Reads tokens from a file that something else keeps up to date.
+struct TokenFile {
+    path: PathBuf,
+}
+
+impl TokenFile {
+    async fn read_token(&self) -> Result<String> {
+        let contents = tokio::fs::read_to_string(&self.path)
+            .await
+            .map_err(|e| Error::Io(e, format!("Reading {}", self.path.display())))?;
+
+        non_empty_token(contents, || self.path.display().to_string())
+    }
+}
+
+impl TokenProvider for TokenFile {
+    fn name(&self) -> &'static str {
+        "token file"
+    }
+
+    fn fetch_token(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(self.read_token())
+    }
+}
+
+/// Runs a command that prints a token.
+struct Command {
+    name: &'static str,
+    program: String,
+    args: Vec<String>,
+}
+
+impl Command {
+    /// Runs a command through the shell.
+    fn shell(command: &str) -> Self {
+        Self {
+            name: "token command",
+            program: "sh".to_owned(),
+            args: vec!["-c".to_owned(), command.to_owned()],
+        }
+    }
+
+    /// Requests a token from the Buildkite OIDC provider through the agent.
+    fn buildkite() -> Self {
+        Self {
+            name: "Buildkite",
+            program: "buildkite-agent".to_owned(),
+            args: ["oidc", "request-token", "--audience", FLAKEHUB_AUDIENCE]
+                .map(str::to_owned)
+                .to_vec(),
+        }
+    }
+
+    async fn run(&self) -> Result<String> {
+        let output = tokio::process::Command::new(&self.program)
+            .args(&self.args)
+            .stdin(std::process::Stdio::null())
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .await
+            .map_err(|e| Error::Io(e, format!("Running {}", self.program)))?;
+
+        if !output.status.success() {
+            return Err(Error::Internal(format!(
+                "{} exited with {}",
+                self.program, output.status
+            )));
+        }
+
+        let stdout = String::from_utf8(output.stdout).map_err(|_| {
+            Error::Internal(format!("{} printed a token that isn't UTF-8", self.program))
+        })?;
+
+        non_empty_token(stdout, || self.program.clone())
+    }
+}
+
+impl TokenProvider for Command {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn fetch_token(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(self.run())
+    }
+}
+
+fn non_empty_token(token: String, source: impl FnOnce() -> String) -> Result<String> {
+    let token = token.trim();
+
+    if token.is_empty() {
+        return Err(Error::Internal(format!(
+            "Got an empty token from {}",
+            source()
+        )));
+    }
+
+    Ok(token.to_owned())
+}
@@ -2,10 +2,18 @@
 
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
 
-use attic::nix_store::NixStore;
+use attic::nix_store::{NixStore, StorePath, ValidPathInfo};
+use futures::stream::{StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, ReadBuf};
 
-use crate::error::Result;
+use crate::error::{Error, Result};
+
+/// The number of path info queries to send to the Nix daemon at the same time.
+const MAX_PATH_INFO_QUERIES: usize = 16;
 
 /// Returns the list of store paths that are currently present.
 pub async fn get_store_paths(store: &NixStore) -> Result<HashSet<PathBuf>> {
@@ -50,3 +58,54 @@ pub async fn get_store_paths(store: &NixStore) -> Result<HashSet<PathBuf>> {
     }
     Ok(paths)
 }
+
+/// Returns the path infos of store paths, in the same order.
+pub async fn query_path_infos(
+    store: &NixStore,
+    paths: Vec<StorePath>,
+) -> Result<Vec<(StorePath, ValidPathInfo)>> {
+    futures::stream::iter(paths)
+        .map(|path| async move {
+            let path_info = store.query_path_info(path.clone()).await?;
+            Ok::<_, Error>((path, path_info))
+        })
+        .buffered(MAX_PATH_INFO_QUERIES)
+        .try_collect()
+        .await
+}
+
+/// A reader that computes the SHA-256 hash of everything read through it.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Returns the hash of the data read so far.
+    pub fn finalize(self) -> attic::hash::Hash {
+        attic::hash::Hash::Sha256(self.hasher.finalize().into())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let already_filled = buf.filled().len();
+
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.hasher.update(&buf.filled()[already_filled..]);
+
+        Poll::Ready(Ok(()))
+    }
+}